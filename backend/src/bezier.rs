@@ -0,0 +1,305 @@
+use crate::packet::{Bezier, Coord};
+
+impl std::ops::Add for Coord {
+    type Output = Coord;
+    fn add(self, other: Coord) -> Coord {
+        Coord(self.0 + other.0, self.1 + other.1)
+    }
+}
+
+impl std::ops::Sub for Coord {
+    type Output = Coord;
+    fn sub(self, other: Coord) -> Coord {
+        Coord(self.0 - other.0, self.1 - other.1)
+    }
+}
+
+impl std::ops::Mul<f64> for Coord {
+    type Output = Coord;
+    fn mul(self, scale: f64) -> Coord {
+        Coord(self.0 * scale, self.1 * scale)
+    }
+}
+
+impl Coord {
+    pub fn distance(&self, other: Coord) -> f64 {
+        (self.0 - other.0).hypot(self.1 - other.1)
+    }
+
+    pub fn dot(&self, other: Coord) -> f64 {
+        self.0 * other.0 + self.1 * other.1
+    }
+
+    pub fn length(&self) -> f64 {
+        self.0.hypot(self.1)
+    }
+}
+
+impl Bezier {
+    // position on the curve, t goes from 0 to 1
+    pub fn point(&self, t: f64) -> Coord {
+        let u = 1f64 - t;
+        match *self {
+            Bezier::Bezier2(p0, p1) => p0 * u + p1 * t,
+            Bezier::Bezier3(p0, p1, p2) => p0 * (u * u) + p1 * (2f64 * u * t) + p2 * (t * t),
+            Bezier::Bezier4(p0, p1, p2, p3) => {
+                p0 * (u * u * u)
+                    + p1 * (3f64 * u * u * t)
+                    + p2 * (3f64 * u * t * t)
+                    + p3 * (t * t * t)
+            }
+        }
+    }
+
+    // first derivative against t, pointing in the forward direction of the curve
+    pub fn derivative(&self, t: f64) -> Coord {
+        let u = 1f64 - t;
+        match *self {
+            Bezier::Bezier2(p0, p1) => p1 - p0,
+            Bezier::Bezier3(p0, p1, p2) => (p1 - p0) * (2f64 * u) + (p2 - p1) * (2f64 * t),
+            Bezier::Bezier4(p0, p1, p2, p3) => {
+                (p1 - p0) * (3f64 * u * u) + (p2 - p1) * (6f64 * u * t) + (p3 - p2) * (3f64 * t * t)
+            }
+        }
+    }
+
+    // second derivative against t, zero on straight lines
+    pub fn second_derivative(&self, t: f64) -> Coord {
+        let u = 1f64 - t;
+        match *self {
+            Bezier::Bezier2(_, _) => Coord(0f64, 0f64),
+            Bezier::Bezier3(p0, p1, p2) => (p2 - p1 * 2f64 + p0) * 2f64,
+            Bezier::Bezier4(p0, p1, p2, p3) => {
+                (p2 - p1 * 2f64 + p0) * (6f64 * u) + (p3 - p2 * 2f64 + p1) * (6f64 * t)
+            }
+        }
+    }
+
+    // signed curvature (1 / radius), positive when turning from +x towards +y
+    pub fn curvature(&self, t: f64) -> f64 {
+        let first = self.derivative(t);
+        let second = self.second_derivative(t);
+        (first.0 * second.1 - first.1 * second.0) / first.length().powi(3)
+    }
+
+    // radius of curvature, infinite on straight parts
+    pub fn radius(&self, t: f64) -> f64 {
+        1f64 / self.curvature(t).abs()
+    }
+
+    // the t where the curve bends the most, sampled since the curvature has no simple closed form
+    pub fn tightest_t(&self) -> f64 {
+        const SAMPLES: u32 = 256;
+
+        (0..=SAMPLES)
+            .map(|i| i as f64 / SAMPLES as f64)
+            .filter(|t| self.curvature(*t).is_finite())
+            .max_by(|a, b| {
+                self.curvature(*a)
+                    .abs()
+                    .total_cmp(&self.curvature(*b).abs())
+            })
+            .unwrap_or(0f64)
+    }
+
+    // top left and bottom right corners of the box around the control points, which holds the whole curve
+    pub fn bounds(&self) -> (Coord, Coord) {
+        let points: Vec<Coord> = (*self).into();
+        points
+            .iter()
+            .fold((points[0], points[0]), |(low, high), point| {
+                (
+                    Coord(low.0.min(point.0), low.1.min(point.1)),
+                    Coord(high.0.max(point.0), high.1.max(point.1)),
+                )
+            })
+    }
+
+    // arc length between t = 0 and t = 1
+    pub fn length(&self) -> f64 {
+        self.length_between(0f64, 1f64)
+    }
+
+    // arc length between two values of t, integrating the speed with gauss-legendre quadrature
+    pub fn length_between(&self, from: f64, to: f64) -> f64 {
+        const SEGMENTS: u32 = 16;
+        const NODES: [(f64, f64); 5] = [
+            (0f64, 0.5688888888888889),
+            (-0.5384693101056831, 0.47862867049936647),
+            (0.5384693101056831, 0.47862867049936647),
+            (-0.906179845938664, 0.23692688505618908),
+            (0.906179845938664, 0.23692688505618908),
+        ];
+
+        let step = (to - from) / SEGMENTS as f64;
+        (0..SEGMENTS)
+            .map(|i| {
+                let center = from + (i as f64 + 0.5f64) * step;
+                NODES
+                    .iter()
+                    .map(|(x, weight)| weight * self.derivative(center + x * step / 2f64).length())
+                    .sum::<f64>()
+                    * step
+                    / 2f64
+            })
+            .sum()
+    }
+
+    // t values cutting the curve into pieces of equal arc length, from t = 0 to t = 1
+    pub fn arc_length_table(&self, pieces: usize) -> Vec<f64> {
+        const STEPS: usize = 256;
+
+        // accumulated arc length at t = i / STEPS
+        let mut lengths = vec![0f64];
+        for i in 0..STEPS {
+            let from = i as f64 / STEPS as f64;
+            let to = (i + 1) as f64 / STEPS as f64;
+            lengths.push(lengths[i] + self.length_between(from, to));
+        }
+
+        let total = lengths[STEPS];
+        (0..=pieces)
+            .map(|piece| {
+                let target = total * piece as f64 / pieces as f64;
+                let i = lengths
+                    .partition_point(|length| *length < target)
+                    .clamp(1, STEPS);
+                let span = lengths[i] - lengths[i - 1];
+                let fraction = if span > 0f64 {
+                    (target - lengths[i - 1]) / span
+                } else {
+                    0f64
+                };
+                (i as f64 - 1f64 + fraction) / STEPS as f64
+            })
+            .collect()
+    }
+
+    // the t whose point is closest to target, found by sampling then narrowing down around the best sample
+    pub fn closest_t(&self, target: Coord) -> f64 {
+        const SAMPLES: u32 = 64;
+        const REFINE_STEPS: u32 = 32;
+
+        let mut best_t = (0..=SAMPLES)
+            .map(|i| i as f64 / SAMPLES as f64)
+            .min_by(|a, b| {
+                let a = self.point(*a).distance(target);
+                let b = self.point(*b).distance(target);
+                a.total_cmp(&b)
+            })
+            .unwrap();
+
+        let mut step = 1f64 / SAMPLES as f64;
+        for _ in 0..REFINE_STEPS {
+            step /= 2f64;
+            for t in [best_t - step, best_t + step] {
+                let t = t.clamp(0f64, 1f64);
+                if self.point(t).distance(target) < self.point(best_t).distance(target) {
+                    best_t = t;
+                }
+            }
+        }
+        best_t
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f64 = 1e-6;
+
+    // close to a quarter circle of radius 100 around the origin
+    fn quarter_circle() -> Bezier {
+        const K: f64 = 0.5522847498307936; // control point distance for circular arcs, times the radius
+        Bezier::Bezier4(
+            Coord(100f64, 0f64),
+            Coord(100f64, 100f64 * K),
+            Coord(100f64 * K, 100f64),
+            Coord(0f64, 100f64),
+        )
+    }
+
+    // every control point in the same place
+    fn degenerate() -> Bezier {
+        Bezier::Bezier4(
+            Coord(5f64, 5f64),
+            Coord(5f64, 5f64),
+            Coord(5f64, 5f64),
+            Coord(5f64, 5f64),
+        )
+    }
+
+    #[test]
+    fn straight_length_is_the_distance_between_the_ends() {
+        let line = Bezier::Bezier2(Coord(0f64, 0f64), Coord(30f64, 40f64));
+        assert!((line.length() - 50f64).abs() < TOLERANCE);
+        assert!((line.length_between(0.25f64, 0.75f64) - 25f64).abs() < TOLERANCE);
+        assert_eq!(line.length_between(0.5f64, 0.5f64), 0f64);
+    }
+
+    #[test]
+    fn curved_length_matches_the_arc() {
+        let length = quarter_circle().length();
+        let arc = std::f64::consts::PI * 100f64 / 2f64;
+        assert!((length - arc).abs() / arc < 1e-3, "{}", length);
+    }
+
+    #[test]
+    fn arc_length_table_cuts_equal_lengths() {
+        // bunched up towards the start, so t and arc length are far apart
+        let curve = Bezier::Bezier3(Coord(0f64, 0f64), Coord(10f64, 0f64), Coord(100f64, 50f64));
+        let table = curve.arc_length_table(8);
+        assert_eq!(table.len(), 9);
+        assert!(table[0].abs() < TOLERANCE && (table[8] - 1f64).abs() < TOLERANCE);
+        let piece = curve.length() / 8f64;
+        for pair in table.windows(2) {
+            assert!(pair[0] < pair[1]);
+            assert!((curve.length_between(pair[0], pair[1]) - piece).abs() < 0.01f64);
+        }
+    }
+
+    #[test]
+    fn closest_t_projects_onto_the_curve() {
+        let line = Bezier::Bezier2(Coord(0f64, 0f64), Coord(100f64, 0f64));
+        assert!((line.closest_t(Coord(25f64, 30f64)) - 0.25f64).abs() < TOLERANCE);
+        assert_eq!(line.closest_t(Coord(150f64, 0f64)), 1f64);
+        assert_eq!(line.closest_t(Coord(-50f64, 10f64)), 0f64);
+
+        let t = quarter_circle().closest_t(Coord(200f64, 200f64));
+        assert!((t - 0.5f64).abs() < 1e-3, "{}", t);
+    }
+
+    #[test]
+    fn tightest_t_is_where_the_curve_bends_most() {
+        let arch = Bezier::Bezier3(Coord(0f64, 0f64), Coord(50f64, 100f64), Coord(100f64, 0f64));
+        assert!((arch.tightest_t() - 0.5f64).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn degenerate_curve_has_no_length_and_stays_in_range() {
+        let curve = degenerate();
+        assert_eq!(curve.length(), 0f64);
+        assert!(curve
+            .arc_length_table(4)
+            .iter()
+            .all(|t| (0f64..=1f64).contains(t)));
+        assert!((0f64..=1f64).contains(&curve.closest_t(Coord(0f64, 0f64))));
+        assert_eq!(curve.tightest_t(), 0f64);
+    }
+
+    #[test]
+    fn non_finite_points_are_noticed() {
+        let curve = Bezier::Bezier2(Coord(0f64, 0f64), Coord(f64::NAN, 0f64));
+        assert!(curve.length().is_nan());
+        assert!(!curve.radius(curve.tightest_t()).is_finite());
+
+        let line = Bezier::Bezier2(Coord(0f64, 0f64), Coord(100f64, 0f64));
+        for target in [
+            Coord(f64::NAN, 0f64),
+            Coord(f64::INFINITY, f64::NEG_INFINITY),
+        ] {
+            assert!((0f64..=1f64).contains(&line.closest_t(target)));
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+
+use crate::packet::ClickModifier;
+
+// what happens to two trains that run into each other
+#[derive(Debug, Clone, Copy, Eq, PartialEq, ValueEnum)]
+pub enum CollisionPolicy {
+    Derail, // both fly off their tracks
+    Stop,   // both stop where they are, clicks get them going again
+    Bounce, // trains heading into the other turn back
+}
+
+// what clicking a train does, a derailed train only takes rerail and ignores the rest
+#[derive(Debug, Clone, Copy, Eq, PartialEq, ValueEnum)]
+pub enum ClickAction {
+    Boost,     // speeds up, up to the train's max speed
+    Reverse,   // turns back where it is
+    StopStart, // brakes to a halt, or gets going again when stopped
+    Derail,    // flies off its track
+    Junction,  // switches the next junction the train will run into
+    Rerail,    // puts a derailed train back on its track
+}
+
+// a combination of held keys and what clicking with them does, written like ctrl+shift=derail,
+// with plain standing for no keys held
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ClickBinding {
+    pub modifier: ClickModifier,
+    pub action: ClickAction,
+}
+
+impl std::str::FromStr for ClickBinding {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<ClickBinding, Self::Err> {
+        let (keys, action) = input
+            .split_once('=')
+            .ok_or("click binding has to look like keys=action")?;
+
+        let (mut ctrl, mut shift, mut alt) = (false, false, false);
+        if keys != "plain" {
+            for key in keys.split('+') {
+                let held = match key {
+                    "ctrl" => &mut ctrl,
+                    "shift" => &mut shift,
+                    "alt" => &mut alt,
+                    _ => {
+                        return Err(format!(
+                            "unknown key {:?}, use ctrl, shift, alt or plain",
+                            key
+                        ))
+                    }
+                };
+                *held = true;
+            }
+        }
+
+        Ok(ClickBinding {
+            modifier: ClickModifier::new(ctrl, shift, alt),
+            action: ClickAction::from_str(action, true)?,
+        })
+    }
+}
+
+// every option can also be given through the environment, flags win over environment variables,
+// relative paths are taken from the directory the server is started in, the backend directory by default
+#[derive(Debug, Clone, Parser)]
+#[command(about = "Train game server: serves the frontend and runs the train simulation")]
+pub struct Config {
+    /// Address the web server listens on
+    #[arg(long, env = "TRAIN_SITE_LOCATION", default_value = "0.0.0.0:8080")]
+    pub bind: String,
+
+    /// Directory holding the frontend files
+    #[arg(long, env = "TRAIN_ASSETS_DIR", default_value = "../frontend")]
+    pub assets_dir: PathBuf,
+
+    /// Layout file with the tracks, junctions and trains to start with
+    #[arg(long, env = "TRAIN_LAYOUT", default_value = "layout.json")]
+    pub layout: PathBuf,
+
+    /// Seconds between checks of the layout file for changes, 0 turns off reloading on change
+    #[arg(long, env = "TRAIN_WATCH_INTERVAL", default_value_t = 1f64)]
+    pub watch_interval: f64,
+
+    /// Snapshot of the world, restored on startup in place of the layout file when present
+    #[arg(long, env = "TRAIN_STATE", default_value = "state.json")]
+    pub state: PathBuf,
+
+    /// Seconds between snapshots of the world, 0 only saves on shutdown
+    #[arg(long, env = "TRAIN_SAVE_INTERVAL", default_value_t = 10f64)]
+    pub save_interval: f64,
+
+    /// What happens to trains that run into each other
+    #[arg(long, env = "TRAIN_COLLISION_POLICY", value_enum, default_value_t = CollisionPolicy::Bounce)]
+    pub collision_policy: CollisionPolicy,
+
+    /// What clicking a train does for each combination of held keys, as pairs like plain=boost or ctrl+shift=derail
+    #[arg(
+        long = "click",
+        env = "TRAIN_CLICK_ACTIONS",
+        value_delimiter = ',',
+        default_value = "plain=boost,shift=stop-start,ctrl=reverse,alt=junction,ctrl+alt=derail,ctrl+shift=rerail"
+    )]
+    pub click_actions: Vec<ClickBinding>,
+
+    /// Lay the tracks out over the registered screens instead of taking them from the layout file,
+    /// laying them anew whenever a screen joins or leaves
+    #[arg(long, env = "TRAIN_GENERATE_TRACKS")]
+    pub generate_tracks: bool,
+
+    /// Token admin pages and requests have to carry as `?token=...`, leave unset to keep them open
+    #[arg(long, env = "TRAIN_ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
+
+    /// Don't serve admin pages and requests at all, the /screens list of registered screens included
+    #[arg(long, env = "TRAIN_DISABLE_ADMIN")]
+    pub disable_admin: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binding(ctrl: bool, shift: bool, alt: bool, action: ClickAction) -> ClickBinding {
+        ClickBinding {
+            modifier: ClickModifier::new(ctrl, shift, alt),
+            action,
+        }
+    }
+
+    #[test]
+    fn bindings_parse() {
+        assert_eq!(
+            "plain=boost".parse(),
+            Ok(binding(false, false, false, ClickAction::Boost))
+        );
+        assert_eq!(
+            "shift=stop-start".parse(),
+            Ok(binding(false, true, false, ClickAction::StopStart))
+        );
+        assert_eq!(
+            "ctrl+alt=derail".parse(),
+            Ok(binding(true, false, true, ClickAction::Derail))
+        );
+        // keys may come in any order
+        assert_eq!(
+            "alt+shift+ctrl=junction".parse(),
+            Ok(binding(true, true, true, ClickAction::Junction))
+        );
+    }
+
+    #[test]
+    fn bad_bindings_are_rejected() {
+        for input in [
+            "",
+            "boost",
+            "=boost",
+            "ctrl+=reverse",
+            "meta=reverse",
+            "plain+ctrl=reverse",
+            "ctrl=",
+            "ctrl=fly",
+        ] {
+            assert!(input.parse::<ClickBinding>().is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn default_bindings_parse() {
+        let config = Config::try_parse_from(["train-backend"]).unwrap();
+        assert_eq!(
+            config.click_actions,
+            [
+                binding(false, false, false, ClickAction::Boost),
+                binding(false, true, false, ClickAction::StopStart),
+                binding(true, false, false, ClickAction::Reverse),
+                binding(false, false, true, ClickAction::Junction),
+                binding(true, false, true, ClickAction::Derail),
+                binding(true, true, false, ClickAction::Rerail),
+            ]
+        );
+    }
+}
@@ -0,0 +1,399 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::layout::{TrackLayout, TRACK_COLOR, TRACK_THICKNESS};
+use crate::packet::*;
+
+// how far apart the two lanes crossing every screen are
+const LANE_SPACING: f64 = 100f64; // px
+
+// share of the screen width left clear of lane on either side, for the curves joining screens to bend in
+const LANE_INSET: f64 = 0.125f64;
+
+// every screen owns this many track and node ids, starting from its slot times this
+const IDS_PER_SCREEN: u32 = 6;
+
+// lays a double track over the screens, snaking through them row by row: left to right along the top
+// row, right to left along the one below and so on. An outbound lane runs across every screen the way
+// its row goes and a return lane the other way, curves join the lanes of neighbouring screens, turn down
+// to the next row at the end of one and turn back at either end of the line, all meeting with matching
+// tangents so trains run through without a kink
+//
+// every screen keeps its slot while it stays registered, so its lanes keep their ids and shape
+// however many screens come and go around it, and only the curves next to a change are laid anew
+//
+// the tracks only meet end to end, there are no junctions among them, and they're given out whole
+// every time, so whoever lays them has to work out which of its tracks are still the same
+#[derive(Default)]
+pub struct Generator {
+    slots: BTreeMap<String, u32>, // screen name to slot
+}
+
+// where a screen's lanes start and end
+struct Lanes {
+    base: u32,     // first track and node id of the screen
+    middle: Coord, // middle of the screen
+    bottom: f64,   // lower edge of the screen
+    upper_left: Coord,
+    upper_right: Coord,
+    lower_left: Coord,
+    lower_right: Coord,
+}
+
+impl Lanes {
+    fn new(base: u32, offset: Coord, size: Coord) -> Self {
+        let left = offset.0 + size.0 * LANE_INSET;
+        let right = offset.0 + size.0 * (1f64 - LANE_INSET);
+        let middle = offset.1 + size.1 / 2f64;
+        let upper = middle - LANE_SPACING / 2f64;
+        let lower = middle + LANE_SPACING / 2f64;
+        Lanes {
+            base,
+            middle: offset + size * 0.5f64,
+            bottom: offset.1 + size.1,
+            upper_left: Coord(left, upper),
+            upper_right: Coord(right, upper),
+            lower_left: Coord(left, lower),
+            lower_right: Coord(right, lower),
+        }
+    }
+
+    // nodes at the lane ends, trains run rightwards on the upper lane and leftwards on the lower one,
+    // so the upper lane is the outbound one in rows running rightwards and the lower one in the others
+    fn upper_left_node(&self) -> NodeID {
+        self.base
+    }
+
+    fn upper_right_node(&self) -> NodeID {
+        self.base + 1
+    }
+
+    fn lower_right_node(&self) -> NodeID {
+        self.base + 2
+    }
+
+    fn lower_left_node(&self) -> NodeID {
+        self.base + 3
+    }
+}
+
+fn track(id: TrackID, path: Bezier, start: NodeID, end: NodeID) -> TrackLayout {
+    TrackLayout {
+        id,
+        path,
+        color: TRACK_COLOR.into(),
+        thickness: TRACK_THICKNESS,
+        start,
+        end,
+        block: None,
+    }
+}
+
+// a curve leaving from heading horizontally the way of heading and arriving at to heading the same way,
+// the control points lie on those headings so the curve meets straight lanes smoothly
+fn joining_curve(from: Coord, to: Coord, heading: f64) -> Bezier {
+    let reach = (from.distance(to) / 3f64).max(LANE_SPACING / 2f64) * heading;
+    Bezier::Bezier4(from, from + Coord(reach, 0f64), to - Coord(reach, 0f64), to)
+}
+
+// a curve leaving from heading horizontally the way of heading and coming back to to the other way,
+// close to a half circle between the lanes
+fn turning_curve(from: Coord, to: Coord, heading: f64) -> Bezier {
+    let reach = from.distance(to) * 2f64 / 3f64 * heading;
+    Bezier::Bezier4(from, from + Coord(reach, 0f64), to + Coord(reach, 0f64), to)
+}
+
+impl Generator {
+    // tracks over the screens, given by name with their world position (px) and size (px)
+    pub fn generate(&mut self, screens: &BTreeMap<String, (Coord, Coord)>) -> Vec<TrackLayout> {
+        // screens that left free their slots, new screens take the lowest free ones
+        self.slots.retain(|name, _| screens.contains_key(name));
+        for name in screens.keys() {
+            if self.slots.contains_key(name) {
+                continue;
+            }
+            let taken: BTreeSet<u32> = self.slots.values().copied().collect();
+            let slot = (0..).find(|slot| !taken.contains(slot)).unwrap();
+            self.slots.insert(name.clone(), slot);
+        }
+
+        let mut lanes: Vec<Lanes> = screens
+            .iter()
+            .map(|(name, (offset, size))| {
+                Lanes::new(self.slots[name] * IDS_PER_SCREEN, *offset, *size)
+            })
+            .collect();
+        // top to bottom, a screen whose middle is above the lower edge of the first screen of the row
+        // above joins that row
+        lanes.sort_by(|a, b| {
+            a.middle
+                .1
+                .partial_cmp(&b.middle.1)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let mut rows: Vec<Vec<Lanes>> = Vec::new();
+        for screen in lanes {
+            match rows.last_mut() {
+                Some(row) if screen.middle.1 <= row[0].bottom => row.push(screen),
+                _ => rows.push(vec![screen]),
+            }
+        }
+        // the order the outbound lane passes the screens in, with whether their row runs rightwards
+        let mut order: Vec<(usize, Lanes)> = Vec::new();
+        for (index, mut row) in rows.into_iter().enumerate() {
+            row.sort_by(|a, b| {
+                a.middle
+                    .0
+                    .partial_cmp(&b.middle.0)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            });
+            if index % 2 == 1 {
+                row.reverse();
+            }
+            order.extend(row.into_iter().map(|screen| (index, screen)));
+        }
+
+        let mut tracks = Vec::new();
+        for (i, (row, screen)) in order.iter().enumerate() {
+            let base = screen.base;
+            let rightwards = row % 2 == 0;
+            tracks.push(track(
+                base,
+                Bezier::Bezier2(screen.upper_left, screen.upper_right),
+                screen.upper_left_node(),
+                screen.upper_right_node(),
+            ));
+            tracks.push(track(
+                base + 1,
+                Bezier::Bezier2(screen.lower_right, screen.lower_left),
+                screen.lower_right_node(),
+                screen.lower_left_node(),
+            ));
+
+            match order.get(i + 1) {
+                // the next screen along the row, joined lane to lane whichever way the row runs
+                Some((next_row, next)) if next_row == row => {
+                    let (left, right) = match rightwards {
+                        true => (screen, next),
+                        false => (next, screen),
+                    };
+                    tracks.push(track(
+                        base + 2,
+                        joining_curve(left.upper_right, right.upper_left, 1f64),
+                        left.upper_right_node(),
+                        right.upper_left_node(),
+                    ));
+                    tracks.push(track(
+                        base + 3,
+                        joining_curve(right.lower_left, left.lower_right, -1f64),
+                        right.lower_left_node(),
+                        left.lower_right_node(),
+                    ));
+                }
+                // the end of a row running rightwards, turning down to the row below on the right
+                Some((_, next)) if rightwards => {
+                    tracks.push(track(
+                        base + 2,
+                        turning_curve(screen.upper_right, next.lower_right, 1f64),
+                        screen.upper_right_node(),
+                        next.lower_right_node(),
+                    ));
+                    tracks.push(track(
+                        base + 3,
+                        turning_curve(next.upper_right, screen.lower_right, 1f64),
+                        next.upper_right_node(),
+                        screen.lower_right_node(),
+                    ));
+                }
+                // the end of a row running leftwards, turning down to the row below on the left
+                Some((_, next)) => {
+                    tracks.push(track(
+                        base + 2,
+                        turning_curve(screen.lower_left, next.upper_left, -1f64),
+                        screen.lower_left_node(),
+                        next.upper_left_node(),
+                    ));
+                    tracks.push(track(
+                        base + 3,
+                        turning_curve(next.lower_left, screen.upper_left, -1f64),
+                        next.lower_left_node(),
+                        screen.upper_left_node(),
+                    ));
+                }
+                None if rightwards => tracks.push(track(
+                    base + 4,
+                    turning_curve(screen.upper_right, screen.lower_right, 1f64),
+                    screen.upper_right_node(),
+                    screen.lower_right_node(),
+                )),
+                None => tracks.push(track(
+                    base + 5,
+                    turning_curve(screen.lower_left, screen.upper_left, -1f64),
+                    screen.lower_left_node(),
+                    screen.upper_left_node(),
+                )),
+            }
+
+            if i == 0 {
+                tracks.push(track(
+                    base + 5,
+                    turning_curve(screen.lower_left, screen.upper_left, -1f64),
+                    screen.lower_left_node(),
+                    screen.upper_left_node(),
+                ));
+            }
+        }
+        tracks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::Layout;
+
+    fn screens(list: &[(&str, Coord, Coord)]) -> BTreeMap<String, (Coord, Coord)> {
+        list.iter()
+            .map(|(name, offset, size)| (name.to_string(), (*offset, *size)))
+            .collect()
+    }
+
+    // every node joins the end of exactly one track to the start of exactly one other, in the same
+    // place and heading the same way
+    fn assert_smooth_loop(tracks: &[TrackLayout]) {
+        let mut nodes: BTreeMap<NodeID, (Vec<&TrackLayout>, Vec<&TrackLayout>)> = BTreeMap::new();
+        for track in tracks {
+            nodes.entry(track.end).or_default().0.push(track);
+            nodes.entry(track.start).or_default().1.push(track);
+        }
+        for (node, (ending, starting)) in nodes {
+            assert_eq!((ending.len(), starting.len()), (1, 1), "node#{}", node);
+            let (before, after) = (ending[0], starting[0]);
+            assert!(
+                before.path.point(1f64).distance(after.path.point(0f64)) < 1e-9,
+                "track#{} and track#{} don't meet",
+                before.id,
+                after.id
+            );
+            let (exit, entry) = (before.path.derivative(1f64), after.path.derivative(0f64));
+            let alignment = exit.dot(entry) / exit.length() / entry.length();
+            assert!(
+                (alignment - 1f64).abs() < 1e-9,
+                "track#{} and track#{} meet at an angle",
+                before.id,
+                after.id
+            );
+        }
+    }
+
+    fn assert_valid(tracks: &[TrackLayout]) {
+        let layout = Layout {
+            tracks: tracks.to_vec(),
+            ..Default::default()
+        };
+        if let Err(errors) = layout.validate() {
+            panic!("{}", errors);
+        }
+    }
+
+    // no curve bends much tighter than the half circle turning between the two lanes of a screen
+    fn assert_gentle(tracks: &[TrackLayout]) {
+        for track in tracks {
+            let radius = track.path.radius(track.path.tightest_t());
+            assert!(
+                radius > LANE_SPACING * 0.4f64,
+                "track#{} bends to {}px",
+                track.id,
+                radius
+            );
+        }
+    }
+
+    #[test]
+    fn one_screen_is_a_smooth_loop() {
+        let tracks = Generator::default().generate(&screens(&[(
+            "a",
+            Coord(0f64, 0f64),
+            Coord(800f64, 600f64),
+        )]));
+        assert_eq!(tracks.len(), 4);
+        assert_smooth_loop(&tracks);
+        assert_valid(&tracks);
+    }
+
+    #[test]
+    fn screens_of_every_size_and_height_make_a_smooth_loop() {
+        let tracks = Generator::default().generate(&screens(&[
+            ("a", Coord(0f64, 0f64), Coord(800f64, 600f64)),
+            ("b", Coord(900f64, 300f64), Coord(400f64, 900f64)),
+            ("c", Coord(1300f64, -200f64), Coord(1920f64, 1080f64)),
+        ]));
+        assert_eq!(tracks.len(), 3 * 2 + 2 * 2 + 2);
+        assert_smooth_loop(&tracks);
+        assert_valid(&tracks);
+    }
+
+    #[test]
+    fn screens_keep_their_lanes_when_others_come_and_go() {
+        let mut generator = Generator::default();
+        let a = ("a", Coord(1000f64, 0f64), Coord(800f64, 600f64));
+        let b = ("b", Coord(0f64, 0f64), Coord(800f64, 600f64));
+        let c = ("c", Coord(2000f64, 0f64), Coord(800f64, 600f64));
+        let lanes = |tracks: &[TrackLayout], base: TrackID| {
+            tracks
+                .iter()
+                .filter(|track| track.id == base || track.id == base + 1)
+                .map(|track| track.path)
+                .collect::<Vec<_>>()
+        };
+
+        let alone = generator.generate(&screens(&[a]));
+        let joined = generator.generate(&screens(&[a, b, c]));
+        assert_smooth_loop(&joined);
+        assert_eq!(lanes(&alone, 0), lanes(&joined, 0));
+
+        // a leaving frees its slot for the next screen to join, the others stay put
+        let left = generator.generate(&screens(&[b, c]));
+        assert_smooth_loop(&left);
+        assert_eq!(lanes(&joined, IDS_PER_SCREEN), lanes(&left, IDS_PER_SCREEN));
+        let d = ("d", Coord(3000f64, 0f64), Coord(800f64, 600f64));
+        let rejoined = generator.generate(&screens(&[b, c, d]));
+        assert_eq!(
+            lanes(&rejoined, 0),
+            lanes(&Generator::default().generate(&screens(&[d])), 0)
+        );
+    }
+
+    #[test]
+    fn a_column_of_screens_is_a_smooth_loop() {
+        let tracks = Generator::default().generate(&screens(&[
+            ("a", Coord(0f64, 0f64), Coord(800f64, 600f64)),
+            ("b", Coord(0f64, 700f64), Coord(800f64, 600f64)),
+            ("c", Coord(100f64, 1400f64), Coord(600f64, 400f64)),
+        ]));
+        assert_eq!(tracks.len(), 3 * 2 + 2 * 2 + 2);
+        assert_smooth_loop(&tracks);
+        assert_valid(&tracks);
+        assert_gentle(&tracks);
+    }
+
+    #[test]
+    fn a_grid_of_screens_snakes_row_by_row() {
+        let tracks = Generator::default().generate(&screens(&[
+            ("a", Coord(0f64, 0f64), Coord(800f64, 600f64)),
+            ("b", Coord(900f64, 0f64), Coord(800f64, 600f64)),
+            ("c", Coord(0f64, 700f64), Coord(800f64, 600f64)),
+            ("d", Coord(900f64, 750f64), Coord(800f64, 600f64)),
+        ]));
+        assert_eq!(tracks.len(), 4 * 2 + 3 * 2 + 2);
+        assert_smooth_loop(&tracks);
+        assert_valid(&tracks);
+        assert_gentle(&tracks);
+
+        // the top row turns down to the bottom one on the right, which ends turning back on the left
+        let (b, d) = (IDS_PER_SCREEN, IDS_PER_SCREEN * 3);
+        let turn_down = tracks.iter().find(|track| track.id == b + 2).unwrap();
+        assert_eq!((turn_down.start, turn_down.end), (b + 1, d + 2));
+        let c = IDS_PER_SCREEN * 2;
+        assert!(tracks.iter().any(|track| track.id == c + 5));
+    }
+}
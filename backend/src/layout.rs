@@ -0,0 +1,592 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::packet::*;
+
+// how far apart two track ends joined at the same node may be
+const NODE_TOLERANCE: f64 = 1f64; // px
+
+// shorter tracks have no usable direction or curvature and trains would cross them in no time
+pub const MIN_TRACK_LENGTH: f64 = 1f64; // px
+
+// how tracks not drawn from the layout file look, the ones laid by clients or over the screens
+pub const TRACK_COLOR: &str = "#66FFCC";
+pub const TRACK_THICKNESS: f64 = 20f64; // px
+
+// what trains not given in the layout file run at and look like
+const DEFAULT_SPEED: f64 = 250f64; // px/s
+const DEFAULT_IMAGE_FORWARD: &str = "train_right.png";
+const DEFAULT_IMAGE_BACKWARD: &str = "train_left.png";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackLayout {
+    pub id: TrackID,
+    pub path: Bezier,         // px
+    pub color: Color,         // #FFFFFF
+    pub thickness: Thickness, // px
+    pub start: NodeID,        // node at the first point of path
+    pub end: NodeID,          // node at the last point of path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block: Option<BlockID>, // tracks sharing a block are signalled together, alone it's a block numbered after the track
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JunctionLayout {
+    pub id: JunctionID,
+    pub node: NodeID,
+    pub track: TrackID, // trains arriving at node over this track are routed by the junction
+    pub sides: [TrackID; 2],
+    #[serde(default)]
+    pub side: Side,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrainLayout {
+    pub id: TrainID,
+    pub speed: f64, // px/s, cruising speed
+    #[serde(default = "default_max_speed")]
+    pub max_speed: f64, // px/s, clicks won't make the train go faster than this
+    #[serde(default = "default_acceleration")]
+    pub acceleration: f64, // px/s^2
+    #[serde(default = "default_deceleration")]
+    pub deceleration: f64, // px/s^2
+    #[serde(default = "default_train_length")]
+    pub length: f64, // px, trains closer than half their lengths added up collide
+    pub image_forward: ImageSrc,
+    pub image_backward: ImageSrc,
+    pub track: TrackID,
+    pub direction: Direction,
+    #[serde(default)]
+    pub progress: f64, // 0 ~ 1, fraction of the track length
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub derailed: Option<DerailReason>, // derailed trains stay off their track until re-railed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cars: Vec<CarLayout>, // trailing behind the train, first to last
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timetable: Option<TimetableLayout>, // without one the train stops at every station it passes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<StationID>, // the train was sent there and sets junctions to get there
+}
+
+impl TrainLayout {
+    // a train of just the engine, with every property at its default, heading forward from progress
+    pub fn new(id: TrainID, track: TrackID, progress: f64) -> Self {
+        TrainLayout {
+            id,
+            speed: DEFAULT_SPEED,
+            max_speed: default_max_speed(),
+            acceleration: default_acceleration(),
+            deceleration: default_deceleration(),
+            length: default_train_length(),
+            image_forward: DEFAULT_IMAGE_FORWARD.into(),
+            image_backward: DEFAULT_IMAGE_BACKWARD.into(),
+            track,
+            direction: Direction::Forward,
+            progress,
+            derailed: None,
+            cars: Vec::new(),
+            timetable: None,
+            destination: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CarLayout {
+    pub image_forward: ImageSrc,
+    pub image_backward: ImageSrc,
+    #[serde(default = "default_car_spacing")]
+    pub spacing: f64, // px, along the track from the car or train in front
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StationLayout {
+    pub id: StationID,
+    pub name: String,
+    pub track: TrackID,
+    pub progress: f64, // 0 ~ 1, fraction of the track length
+    #[serde(default = "default_dwell")]
+    pub dwell: f64, // s, trains stopping here stay at least this long
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimetableLayout {
+    pub period: f64, // s, the timetable repeats every period counted from the unix epoch
+    pub stops: Vec<StopLayout>, // stations the train stops at in turn, passing by any other
+    #[serde(default)]
+    pub next: usize, // index of the stop the train is heading for
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StopLayout {
+    pub station: StationID,
+    pub departure: f64, // s into the period
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScreenLayout {
+    pub name: String,  // as the screen reports itself in its viewport
+    pub offset: Coord, // px, world position of the top left corner
+    #[serde(default = "default_scale")]
+    pub scale: f64, // screen px per world px
+}
+
+// fast enough to fly off the tighter curves
+fn default_max_speed() -> f64 {
+    1000f64
+}
+
+// a couple of seconds from standstill to the usual cruising speeds
+fn default_acceleration() -> f64 {
+    200f64
+}
+
+// brakes are stronger than the engine
+fn default_deceleration() -> f64 {
+    400f64
+}
+
+// about as long as the train sprites are drawn
+fn default_train_length() -> f64 {
+    80f64
+}
+
+// sprites just clear of each other
+fn default_car_spacing() -> f64 {
+    90f64
+}
+
+// long enough to notice the train has stopped
+fn default_dwell() -> f64 {
+    3f64
+}
+
+// world px drawn as they are
+fn default_scale() -> f64 {
+    1f64
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Layout {
+    #[serde(default)]
+    pub tracks: Vec<TrackLayout>,
+    #[serde(default)]
+    pub junctions: Vec<JunctionLayout>,
+    #[serde(default)]
+    pub trains: Vec<TrainLayout>,
+    #[serde(default)]
+    pub stations: Vec<StationLayout>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub screens: Vec<ScreenLayout>, // screens placed by the server, any other places itself
+}
+
+impl Layout {
+    // read and validate a layout file, errors name the file or the offending entry
+    pub fn load(path: &std::path::Path) -> Result<Layout, String> {
+        let content =
+            std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let layout: Layout =
+            serde_json::from_str(&content).map_err(|err| format!("{}: {}", path.display(), err))?;
+        layout.validate()?;
+        Ok(layout)
+    }
+
+    // write the layout through a temporary file, so a crash while saving leaves the old file intact
+    pub fn save(&self, path: &std::path::Path) -> Result<(), String> {
+        let content = serde_json::to_string_pretty(self).map_err(|err| err.to_string())?;
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, content)
+            .and_then(|_| std::fs::rename(&temporary, path))
+            .map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    // check everything that can't be expressed by the file structure alone, reporting every problem found
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+
+        let mut tracks = BTreeMap::new();
+        let mut nodes: BTreeMap<NodeID, (TrackID, Coord)> = BTreeMap::new();
+        for track in &self.tracks {
+            if tracks.insert(track.id, track).is_some() {
+                errors.push(format!("track#{}: duplicated track id", track.id));
+            }
+
+            let is_color = track.color.len() == 7
+                && track.color.starts_with('#')
+                && track.color[1..].chars().all(|c| c.is_ascii_hexdigit());
+            if !is_color {
+                errors.push(format!(
+                    "track#{}: color {:?} is not in #RRGGBB form",
+                    track.id, track.color
+                ));
+            }
+
+            if track.thickness.is_nan() || track.thickness <= 0f64 {
+                errors.push(format!("track#{}: thickness has to be positive", track.id));
+            }
+
+            if track.start == track.end {
+                errors.push(format!(
+                    "track#{}: starts and ends at the same node#{}",
+                    track.id, track.start
+                ));
+            }
+
+            if track.path.length().is_nan() || track.path.length() < MIN_TRACK_LENGTH {
+                errors.push(format!(
+                    "track#{}: has to be at least {}px long",
+                    track.id, MIN_TRACK_LENGTH
+                ));
+            }
+
+            for (node, position) in [
+                (track.start, track.path.point(0f64)),
+                (track.end, track.path.point(1f64)),
+            ] {
+                match nodes.get(&node) {
+                    Some((other, other_position)) => {
+                        if position.distance(*other_position) > NODE_TOLERANCE {
+                            errors.push(format!(
+                                "track#{}: joins node#{} at {} but track#{} has it at {}",
+                                track.id, node, position, other, other_position
+                            ));
+                        }
+                    }
+                    None => {
+                        nodes.insert(node, (track.id, position));
+                    }
+                }
+            }
+        }
+
+        let touches = |track_id: &TrackID, node: NodeID| {
+            tracks
+                .get(track_id)
+                .is_some_and(|track| track.start == node || track.end == node)
+        };
+
+        let mut junctions = BTreeMap::new();
+        for junction in &self.junctions {
+            if junctions.insert(junction.id, junction).is_some() {
+                errors.push(format!("junction#{}: duplicated junction id", junction.id));
+            }
+
+            for track_id in [junction.track, junction.sides[0], junction.sides[1]] {
+                if !touches(&track_id, junction.node) {
+                    errors.push(format!(
+                        "junction#{}: track#{} doesn't exist or doesn't reach node#{}",
+                        junction.id, track_id, junction.node
+                    ));
+                }
+            }
+
+            if junction.sides[0] == junction.sides[1] {
+                errors.push(format!(
+                    "junction#{}: both sides lead to the same track",
+                    junction.id
+                ));
+            }
+        }
+
+        let mut stations = BTreeMap::new();
+        for station in &self.stations {
+            if stations.insert(station.id, station).is_some() {
+                errors.push(format!("station#{}: duplicated station id", station.id));
+            }
+
+            if !tracks.contains_key(&station.track) {
+                errors.push(format!(
+                    "station#{}: track#{} doesn't exist",
+                    station.id, station.track
+                ));
+            }
+
+            if !(0f64..=1f64).contains(&station.progress) {
+                errors.push(format!(
+                    "station#{}: progress has to be between 0 and 1",
+                    station.id
+                ));
+            }
+
+            if station.dwell.is_nan() || station.dwell < 0f64 {
+                errors.push(format!("station#{}: dwell can't be negative", station.id));
+            }
+        }
+
+        let mut screens = BTreeSet::new();
+        for screen in &self.screens {
+            if !screens.insert(&screen.name) {
+                errors.push(format!("screen {:?}: duplicated screen name", screen.name));
+            }
+
+            if !(screen.offset.0.is_finite() && screen.offset.1.is_finite()) {
+                errors.push(format!("screen {:?}: offset has to be finite", screen.name));
+            }
+
+            if !(screen.scale.is_finite() && screen.scale > 0f64) {
+                errors.push(format!(
+                    "screen {:?}: scale has to be positive",
+                    screen.name
+                ));
+            }
+        }
+
+        let mut trains = BTreeMap::new();
+        for train in &self.trains {
+            if trains.insert(train.id, train).is_some() {
+                errors.push(format!("train#{}: duplicated train id", train.id));
+            }
+
+            if !tracks.contains_key(&train.track) {
+                errors.push(format!(
+                    "train#{}: track#{} doesn't exist",
+                    train.id, train.track
+                ));
+            }
+
+            if train.speed.is_nan() || train.speed <= 0f64 {
+                errors.push(format!("train#{}: speed has to be positive", train.id));
+            }
+            if train.max_speed.is_nan() || train.max_speed < train.speed {
+                errors.push(format!(
+                    "train#{}: max_speed can't be lower than speed",
+                    train.id
+                ));
+            }
+            if train.acceleration.is_nan() || train.acceleration <= 0f64 {
+                errors.push(format!(
+                    "train#{}: acceleration has to be positive",
+                    train.id
+                ));
+            }
+            if train.deceleration.is_nan() || train.deceleration <= 0f64 {
+                errors.push(format!(
+                    "train#{}: deceleration has to be positive",
+                    train.id
+                ));
+            }
+            if train.length.is_nan() || train.length <= 0f64 {
+                errors.push(format!("train#{}: length has to be positive", train.id));
+            }
+
+            for (i, car) in train.cars.iter().enumerate() {
+                if car.spacing.is_nan() || car.spacing <= 0f64 {
+                    errors.push(format!(
+                        "train#{}: car#{} spacing has to be positive",
+                        train.id, i
+                    ));
+                }
+            }
+
+            if !(0f64..=1f64).contains(&train.progress) {
+                errors.push(format!(
+                    "train#{}: progress has to be between 0 and 1",
+                    train.id
+                ));
+            }
+
+            if let Some(station_id) = train.destination {
+                if !stations.contains_key(&station_id) {
+                    errors.push(format!(
+                        "train#{}: destination station#{} doesn't exist",
+                        train.id, station_id
+                    ));
+                }
+            }
+
+            if let Some(timetable) = &train.timetable {
+                if timetable.period.is_nan() || timetable.period <= 0f64 {
+                    errors.push(format!(
+                        "train#{}: timetable period has to be positive",
+                        train.id
+                    ));
+                }
+                if timetable.stops.is_empty() {
+                    errors.push(format!("train#{}: timetable has no stops", train.id));
+                }
+                if timetable.next >= timetable.stops.len().max(1) {
+                    errors.push(format!(
+                        "train#{}: timetable has no stop#{}",
+                        train.id, timetable.next
+                    ));
+                }
+                for (i, stop) in timetable.stops.iter().enumerate() {
+                    if !stations.contains_key(&stop.station) {
+                        errors.push(format!(
+                            "train#{}: stop#{} is at station#{} which doesn't exist",
+                            train.id, i, stop.station
+                        ));
+                    }
+                    if !(0f64..timetable.period).contains(&stop.departure) {
+                        errors.push(format!(
+                            "train#{}: stop#{} departure has to be within the period",
+                            train.id, i
+                        ));
+                    }
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(id: TrackID, path: Bezier, start: NodeID, end: NodeID) -> TrackLayout {
+        TrackLayout {
+            id,
+            path,
+            color: "#FFFFFF".into(),
+            thickness: 10f64,
+            start,
+            end,
+            block: None,
+        }
+    }
+
+    // two tracks in a line and a train on the first one
+    fn layout() -> Layout {
+        Layout {
+            tracks: vec![
+                track(
+                    0,
+                    Bezier::Bezier2(Coord(0f64, 0f64), Coord(100f64, 0f64)),
+                    0,
+                    1,
+                ),
+                track(
+                    1,
+                    Bezier::Bezier2(Coord(100f64, 0f64), Coord(200f64, 0f64)),
+                    1,
+                    2,
+                ),
+            ],
+            trains: vec![TrainLayout::new(0, 0, 0.5f64)],
+            ..Default::default()
+        }
+    }
+
+    // what validate reports, one problem per line
+    fn errors(layout: &Layout) -> Vec<String> {
+        match layout.validate() {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors.lines().map(String::from).collect(),
+        }
+    }
+
+    #[test]
+    fn shipped_layout_is_valid() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("layout.json");
+        if let Err(errors) = Layout::load(&path) {
+            panic!("{}", errors);
+        }
+    }
+
+    #[test]
+    fn valid_layout_passes() {
+        assert_eq!(errors(&layout()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn saved_layout_loads_back_the_same() {
+        let path = std::env::temp_dir().join(format!("train-backend-{}.json", std::process::id()));
+        let layout = layout();
+        layout.save(&path).unwrap();
+        let loaded = Layout::load(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            serde_json::to_value(loaded.unwrap()).unwrap(),
+            serde_json::to_value(&layout).unwrap()
+        );
+    }
+
+    #[test]
+    fn track_looping_onto_its_own_node_is_reported() {
+        let mut layout = layout();
+        layout.tracks[1] = track(
+            1,
+            Bezier::Bezier4(
+                Coord(100f64, 0f64),
+                Coord(200f64, 0f64),
+                Coord(200f64, 100f64),
+                Coord(100f64, 0f64),
+            ),
+            1,
+            1,
+        );
+        assert_eq!(
+            errors(&layout),
+            ["track#1: starts and ends at the same node#1"]
+        );
+    }
+
+    #[test]
+    fn track_without_length_is_reported() {
+        let mut layout = layout();
+        layout.tracks[1] = track(
+            1,
+            Bezier::Bezier2(Coord(100f64, 0f64), Coord(100f64, 0f64)),
+            1,
+            2,
+        );
+        assert_eq!(
+            errors(&layout),
+            [format!(
+                "track#1: has to be at least {}px long",
+                MIN_TRACK_LENGTH
+            )]
+        );
+    }
+
+    #[test]
+    fn track_with_non_finite_points_is_reported() {
+        let mut layout = layout();
+        layout.tracks[1].path = Bezier::Bezier2(Coord(100f64, 0f64), Coord(f64::NAN, 0f64));
+        assert!(errors(&layout).contains(&format!(
+            "track#1: has to be at least {}px long",
+            MIN_TRACK_LENGTH
+        )));
+    }
+
+    #[test]
+    fn tracks_disagreeing_on_a_node_are_reported() {
+        let mut layout = layout();
+        layout.tracks[1].path = Bezier::Bezier2(Coord(150f64, 0f64), Coord(200f64, 0f64));
+        assert_eq!(
+            errors(&layout),
+            ["track#1: joins node#1 at 150;0 but track#0 has it at 100;0"]
+        );
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let mut layout = layout();
+        layout.tracks.push(track(0, layout.tracks[0].path, 0, 1));
+        layout.junctions.push(JunctionLayout {
+            id: 0,
+            node: 1,
+            track: 0,
+            sides: [1, 5],
+            side: false,
+        });
+        layout.trains[0].speed = f64::NAN;
+        layout.trains[0].track = 7;
+        assert_eq!(
+            errors(&layout),
+            [
+                "track#0: duplicated track id",
+                "junction#0: track#5 doesn't exist or doesn't reach node#1",
+                "train#0: track#7 doesn't exist",
+                "train#0: speed has to be positive",
+            ]
+        );
+    }
+}
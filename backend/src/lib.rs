@@ -1 +1,7 @@
+pub mod bezier;
+pub mod config;
+pub mod generator;
+pub mod layout;
 pub mod packet;
+pub mod track;
+pub mod train;
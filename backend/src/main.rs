@@ -1,22 +1,145 @@
-use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::collections::{BTreeMap, BTreeSet};
 
-use axum::extract::State;
+use axum::extract::{Query, Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
 use axum::{extract::ws, routing::get, Router};
+use btreemultimap::BTreeMultiMap;
+use clap::Parser;
+use rand::seq::IteratorRandom;
 
 use tokio::sync::{mpsc, oneshot, watch};
 
+use train_backend::config::{ClickAction, CollisionPolicy, Config};
+use train_backend::generator::Generator;
+use train_backend::layout::{
+    CarLayout, JunctionLayout, Layout, ScreenLayout, StationLayout, StopLayout, TimetableLayout,
+    TrackLayout, TrainLayout, MIN_TRACK_LENGTH, TRACK_COLOR, TRACK_THICKNESS,
+};
 use train_backend::packet::*;
+use train_backend::track::{
+    junction_ahead, kept_tracks, next_junction, next_track, node_at, node_position, place_stations,
+    plan_route, Junction, Station, TrackPiece,
+};
+use train_backend::train::{
+    closest_cars, contact_time, train_properties, upcoming_arrivals, StationArrivals, Target,
+    TrainInstance, COLLISION_PROBE,
+};
+
+// a new viewer asks train master for its serial, an update stream and a way to send client packets back,
+// which go tagged with the serial
+type ViewRequest = oneshot::Sender<(
+    u32,
+    mpsc::Receiver<ServerPacket>,
+    mpsc::Sender<(u32, ClientPacket)>,
+)>;
+
+// train master answers with the current world in layout form
+type SnapshotRequest = oneshot::Sender<Layout>;
+
+// train master answers with the trains expected at every station
+type ArrivalsRequest = oneshot::Sender<Vec<StationArrivals>>;
+
+// train master answers with what every connected screen shows, by viewer serial
+type ScreensRequest = oneshot::Sender<BTreeMap<u32, Screen>>;
+
+// how far around what a screen shows tracks and trains are still sent to it
+const VIEW_MARGIN: f64 = 200f64; // px
+
+// how fast a train has to go to shake the screens it passes as hard as they shake
+const VIBRATION_SPEED: f64 = 500f64; // px/s
+
+// how far off a screen a derailing train is still felt on it
+const CRASH_RANGE: f64 = 600f64; // px
+
+// vibrations weaker than this aren't worth sending
+const MIN_VIBRATION: f64 = 0.05f64;
+
+#[derive(Clone, serde::Serialize)]
+struct Screen {
+    name: Option<String>,
+    offset: Coord, // px, world position of the top left corner
+    size: Coord,   // px
+}
+
+impl Screen {
+    // the box, top left and bottom right corners, is on the screen or within VIEW_MARGIN of it
+    fn sees(&self, (low, high): (Coord, Coord)) -> bool {
+        low.0 <= self.offset.0 + self.size.0 + VIEW_MARGIN
+            && high.0 >= self.offset.0 - VIEW_MARGIN
+            && low.1 <= self.offset.1 + self.size.1 + VIEW_MARGIN
+            && high.1 >= self.offset.1 - VIEW_MARGIN
+    }
+
+    // how hard and how long the screen shakes for something happening to a train at position going at speed,
+    // stronger the closer to the middle of the screen and the faster, not at all off the screen unless it's a crash
+    fn vibration(
+        &self,
+        pattern: VibrationPattern,
+        position: Coord,
+        speed: f64,
+    ) -> Option<(f64, Duration)> {
+        let (range, duration) = match pattern {
+            VibrationPattern::Rumble => (0f64, Duration::from_millis(400)),
+            VibrationPattern::Bump => (0f64, Duration::from_millis(150)),
+            VibrationPattern::Crash => (CRASH_RANGE, Duration::from_millis(1000)),
+        };
+
+        let off_screen = Coord(
+            (self.offset.0 - position.0)
+                .max(position.0 - self.offset.0 - self.size.0)
+                .max(0f64),
+            (self.offset.1 - position.1)
+                .max(position.1 - self.offset.1 - self.size.1)
+                .max(0f64),
+        );
+        if off_screen.length() > range {
+            return None;
+        }
+        let middle = self.offset + self.size * 0.5f64;
+        let closeness =
+            (1f64 - position.distance(middle) / (self.size.length() / 2f64 + range)).max(0f64);
+        let intensity = closeness * (speed / VIBRATION_SPEED).min(1f64);
+        if intensity < MIN_VIBRATION {
+            return None;
+        }
+        Some((intensity, duration))
+    }
+}
 
 #[derive(Clone)]
 struct AppState {
-    view_request_tx: mpsc::Sender<
-        oneshot::Sender<(
-            mpsc::Receiver<ServerPacket>,
-            mpsc::Sender<(TrainID, ClickModifier)>,
-        )>,
-    >,
+    view_request_tx: mpsc::Sender<ViewRequest>,
     valid_id: watch::Receiver<BTreeSet<TrainID>>,
-    derail_tx: mpsc::Sender<()>,
+    derail_tx: mpsc::Sender<Option<TrainID>>,
+    rerail_tx: mpsc::Sender<Option<TrainID>>,
+    reload_tx: mpsc::Sender<Layout>,
+    send_tx: mpsc::Sender<(TrainID, StationID)>,
+    arrivals_tx: mpsc::Sender<ArrivalsRequest>,
+    screens_tx: mpsc::Sender<ScreensRequest>,
+    place_tx: mpsc::Sender<(String, Coord, f64)>,
+    layout_path: std::path::PathBuf,
+    admin_token: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct AdminQuery {
+    token: Option<String>,
+}
+
+// let admin requests through only when they carry the configured token
+async fn admin_guard(
+    State(state): State<AppState>,
+    Query(query): Query<AdminQuery>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match state.admin_token {
+        Some(token) if query.token.as_ref() != Some(&token) => {
+            axum::http::StatusCode::UNAUTHORIZED.into_response()
+        }
+        _ => next.run(request).await,
+    }
 }
 
 async fn ws_get_handler(
@@ -26,8 +149,208 @@ async fn ws_get_handler(
     ws.on_upgrade(|socket| ws_client_handler(socket, state))
 }
 
-async fn derail_handler(State(state): State<AppState>) {
-    let _ = state.derail_tx.send(()).await;
+#[derive(serde::Deserialize)]
+struct TrainQuery {
+    train: Option<TrainID>,
+}
+
+async fn derail_handler(State(state): State<AppState>, Query(query): Query<TrainQuery>) {
+    let _ = state.derail_tx.send(query.train).await;
+}
+
+async fn rerail_handler(State(state): State<AppState>, Query(query): Query<TrainQuery>) {
+    let _ = state.rerail_tx.send(query.train).await;
+}
+
+#[derive(serde::Deserialize)]
+struct SendQuery {
+    train: TrainID,
+    station: StationID,
+}
+
+async fn send_handler(State(state): State<AppState>, Query(query): Query<SendQuery>) {
+    let _ = state.send_tx.send((query.train, query.station)).await;
+}
+
+#[derive(serde::Deserialize)]
+struct PlaceQuery {
+    screen: String,
+    x: f64,
+    y: f64,
+    scale: Option<f64>,
+}
+
+// move a screen to a place in the world, the viewers showing it follow right away
+async fn place_handler(
+    State(state): State<AppState>,
+    Query(query): Query<PlaceQuery>,
+) -> (axum::http::StatusCode, String) {
+    let scale = query.scale.unwrap_or(1f64);
+    if !(query.x.is_finite() && query.y.is_finite() && scale.is_finite() && scale > 0f64) {
+        return (
+            axum::http::StatusCode::BAD_REQUEST,
+            "Offset has to be finite and scale positive".into(),
+        );
+    }
+    let name = query.screen.trim().to_string();
+    if name.is_empty() {
+        return (
+            axum::http::StatusCode::BAD_REQUEST,
+            "Screen name is empty".into(),
+        );
+    }
+    match state
+        .place_tx
+        .send((name, Coord(query.x, query.y), scale))
+        .await
+    {
+        Ok(_) => (axum::http::StatusCode::OK, "Placing screen".into()),
+        Err(_) => (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to reach train master".into(),
+        ),
+    }
+}
+
+async fn reload_handler(State(state): State<AppState>) -> (axum::http::StatusCode, String) {
+    match Layout::load(&state.layout_path) {
+        Ok(layout) => match state.reload_tx.send(layout).await {
+            Ok(_) => (axum::http::StatusCode::OK, "Reloading layout".into()),
+            Err(_) => (
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to reach train master".into(),
+            ),
+        },
+        Err(err) => (axum::http::StatusCode::BAD_REQUEST, err),
+    }
+}
+
+async fn arrivals_handler(
+    State(state): State<AppState>,
+) -> Result<axum::Json<Vec<StationArrivals>>, axum::http::StatusCode> {
+    let (arrivals_tx, arrivals_rx) = oneshot::channel();
+    if state.arrivals_tx.send(arrivals_tx).await.is_err() {
+        return Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    arrivals_rx
+        .await
+        .map(axum::Json)
+        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn screens_handler(
+    State(state): State<AppState>,
+) -> Result<axum::Json<BTreeMap<u32, Screen>>, axum::http::StatusCode> {
+    let (screens_tx, screens_rx) = oneshot::channel();
+    if state.screens_tx.send(screens_tx).await.is_err() {
+        return Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    screens_rx
+        .await
+        .map(axum::Json)
+        .map_err(|_| axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+fn modified(path: &std::path::Path) -> Option<std::time::SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+// path was modified after other was, false when either time can't be told
+fn edited_after(path: &std::path::Path, other: &std::path::Path) -> bool {
+    match (modified(path), modified(other)) {
+        (Some(path), Some(other)) => path > other,
+        _ => false,
+    }
+}
+
+// reload the layout whenever the file's modification time changes
+async fn layout_watcher(
+    path: std::path::PathBuf,
+    interval: Duration,
+    reload_tx: mpsc::Sender<Layout>,
+) {
+    let mut last_modified = modified(&path);
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let current_modified = modified(&path);
+        if current_modified == last_modified {
+            continue;
+        }
+        last_modified = current_modified;
+
+        match Layout::load(&path) {
+            Ok(layout) => {
+                println!("Layout file has changed, reloading...");
+                if reload_tx.send(layout).await.is_err() {
+                    return;
+                }
+            }
+            Err(err) => {
+                println!(
+                    "Layout file has changed but failed to load, keeping the current layout:\n{}",
+                    err
+                );
+            }
+        }
+    }
+}
+
+async fn save_snapshot(snapshot_tx: &mpsc::Sender<SnapshotRequest>, path: &std::path::Path) {
+    let (layout_tx, layout_rx) = oneshot::channel();
+    if snapshot_tx.send(layout_tx).await.is_err() {
+        println!("Failed to request a snapshot, is train master dead?");
+        return;
+    }
+    let layout = match layout_rx.await {
+        Ok(layout) => layout,
+        Err(_) => {
+            println!("Failed to receive a snapshot from train master");
+            return;
+        }
+    };
+    if let Err(err) = layout.save(path) {
+        println!("Failed to save snapshot:\n{}", err);
+    }
+}
+
+async fn snapshot_saver(
+    path: std::path::PathBuf,
+    interval: Duration,
+    snapshot_tx: mpsc::Sender<SnapshotRequest>,
+) {
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        save_snapshot(&snapshot_tx, &path).await;
+    }
+}
+
+// resolves on ctrl-c, or on the terminate signal a redeploy sends
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
 
 async fn ws_client_handler(mut socket: ws::WebSocket, state: AppState) {
@@ -43,7 +366,7 @@ async fn ws_client_handler(mut socket: ws::WebSocket, state: AppState) {
         }
     };
 
-    let (mut update_receiver, click_sender) = match substribe_request_rx.await {
+    let (serial, mut update_receiver, packet_sender) = match substribe_request_rx.await {
         Ok(rx) => rx,
         Err(_) => {
             println!("Failed to subscribe to train updates");
@@ -76,26 +399,24 @@ async fn ws_client_handler(mut socket: ws::WebSocket, state: AppState) {
 
                 let packet = match packet.parse::<ClientPacket>() {
                     Err(err) => {
-                        println!("A websocket connection sent a packet expected to be a CLICK but failed parsing:\n\t{}", err);
+                        println!("A websocket connection sent a packet but failed parsing:\n\t{}", err);
                         break;
                     }
                     Ok(packet) => packet,
                 };
 
-                match packet {
-                    ClientPacket::PacketCLICK(train_id, modifier) => {
-                        if !state.valid_id.borrow().contains(&train_id) {
-                            println!("A websocket connection sent a packet expected to be a CLICK but contains invalid train id");
-                            break;
-                        } else {
-                            match click_sender.send((train_id, modifier)).await {
-                                Ok(_) => (),
-                                Err(_) => {
-                                    println!("Failed sending click updates to train master");
-                                    break;
-                                }
-                            }
-                        }
+                if let ClientPacket::PacketCLICK(train_id, _) = packet {
+                    if !state.valid_id.borrow().contains(&train_id) {
+                        println!("A websocket connection sent a packet expected to be a CLICK but contains invalid train id");
+                        break;
+                    }
+                }
+
+                match packet_sender.send((serial, packet)).await {
+                    Ok(_) => (),
+                    Err(_) => {
+                        println!("Failed sending client packets to train master");
+                        break;
                     }
                 }
             }
@@ -123,480 +444,1325 @@ async fn ws_client_handler(mut socket: ws::WebSocket, state: AppState) {
     // }
 
     let _ = socket.send(ws::Message::Close(Option::None)).await;
-    return;
+}
+
+// requests reaching train master from admin pages and background tasks rather than viewers
+struct AdminReceivers {
+    derail_rx: mpsc::Receiver<Option<TrainID>>,
+    rerail_rx: mpsc::Receiver<Option<TrainID>>,
+    reload_rx: mpsc::Receiver<Layout>,
+    send_rx: mpsc::Receiver<(TrainID, StationID)>,
+    snapshot_rx: mpsc::Receiver<SnapshotRequest>,
+    arrivals_rx: mpsc::Receiver<ArrivalsRequest>,
+    screens_rx: mpsc::Receiver<ScreensRequest>,
+    place_rx: mpsc::Receiver<(String, Coord, f64)>,
 }
 
 async fn train_master(
-    mut view_request_rx: mpsc::Receiver<
-        oneshot::Sender<(
-            mpsc::Receiver<ServerPacket>,
-            mpsc::Sender<(TrainID, ClickModifier)>,
-        )>,
-    >,
+    mut view_request_rx: mpsc::Receiver<ViewRequest>,
     valid_id_tx: watch::Sender<BTreeSet<TrainID>>,
-    mut derail_rx: mpsc::Receiver<()>,
+    mut admin_rx: AdminReceivers,
+    layout: Layout,
+    config: Config,
 ) {
-    struct TrackPiece {
-        path: Bezier,         // px
-        color: Color,         // #FFFFFF
-        thickness: Thickness, // px
-        length: f64,          // px
-    }
-
-    struct TrainProperties {
-        speed: f64, // px/s
-        image_forward: String,
-        image_backward: String,
-    }
-
-    struct TrainInstance {
-        properties: TrainProperties,
-        current_track: u32,
-        progress: f64,        // 0 ~ 1
-        direction: Direction, // backward direction: progress goes from 1 to 0
-    }
-
-    impl TrainInstance {
-        fn estimated_time_left(&self, tracks: &BTreeMap<u32, TrackPiece>) -> Duration {
-            Duration::from_secs_f64(
-                match self.direction {
-                    Direction::Forward => 1f64 - self.progress,
-                    Direction::Backward => self.progress,
-                } * tracks.get(&self.current_track).unwrap().length
-                    / self.properties.speed,
-            )
+    // how much faster a train wants to go every time it's clicked
+    const CLICK_BOOST: f64 = 100f64; // px/s
+
+    // how far into its block a signal stands, so the signals of blocks meeting at a node don't overlap
+    const SIGNAL_OFFSET: f64 = 30f64; // px
+
+    // things bound to happen at a certain time, each carrying the epochs of the trains involved
+    // as they were when it was planned
+    enum Event {
+        Arrival(TrainID, u64),   // a train reaches its target progress
+        Brake(TrainID, u64), // a train starts slowing down for the junction, signal or station ahead
+        Departure(TrainID, u64), // a train leaves the station it's at
+        Collision((TrainID, u64), (TrainID, u64)),
+    }
+
+    struct EventQueue {
+        events: BTreeMultiMap<tokio::time::Instant, Event>,
+    }
+
+    impl EventQueue {
+        fn schedule(&mut self, time: tokio::time::Instant, event: Event) {
+            self.events.insert(time, event);
+        }
+
+        fn next_time(&self) -> Option<tokio::time::Instant> {
+            self.events.keys().next().copied()
+        }
+
+        // the earliest event, if it's due by now
+        fn pop_due(&mut self, now: tokio::time::Instant) -> Option<(tokio::time::Instant, Event)> {
+            let time = self.next_time().filter(|time| *time <= now)?;
+            let mut events = self.events.remove(&time).unwrap();
+            let event = events.remove(0);
+            if !events.is_empty() {
+                self.events.insert_many(time, events);
+            }
+            Some((time, event))
+        }
+    }
+
+    // the train's motion has changed as of time: drop everything planned for it and plan again,
+    // its arrival, braking, departure from a station and any collision with another train before either
+    // of them leaves its track; tells whether its last car has meanwhile left a track or come onto one
+    fn reschedule(
+        id: TrainID,
+        time: tokio::time::Instant,
+        trains: &mut BTreeMap<TrainID, TrainInstance>,
+        tracks: &BTreeMap<u32, TrackPiece>,
+        junctions: &BTreeMap<JunctionID, Junction>,
+        events: &mut EventQueue,
+    ) -> bool {
+        let train = trains.get_mut(&id).unwrap();
+        let trail = train.trail.clone();
+        train.catch_up(time, tracks);
+        train.fill_trail(tracks);
+        train.epoch += 1;
+        let trail_changed = train.trail != trail;
+        if train.derailed.is_some() {
+            return trail_changed;
+        }
+
+        let train = &trains[&id];
+        let arrival = train.arrival_time(tracks);
+        if let Some(arrival) = arrival {
+            events.schedule(arrival, Event::Arrival(id, train.epoch));
+        }
+        if let Some(braking) = train.braking_time(tracks, junctions) {
+            events.schedule(braking, Event::Brake(id, train.epoch));
+        }
+        if let Some((_, departure)) = train.dwelling {
+            events.schedule(departure, Event::Departure(id, train.epoch));
+        }
+
+        for (other_id, other) in trains.iter() {
+            if *other_id == id || other.derailed.is_some() {
+                continue;
+            }
+            let until = match (arrival, other.arrival_time(tracks)) {
+                (Some(a), Some(b)) => a.min(b),
+                (Some(until), None) | (None, Some(until)) => until,
+                (None, None) => time, // neither is moving, only already closing in counts
+            };
+            if let Some(contact) = contact_time(train, other, time, until, tracks) {
+                events.schedule(
+                    contact,
+                    Event::Collision((id, train.epoch), (*other_id, other.epoch)),
+                );
+            }
+        }
+        trail_changed
+    }
+
+    // the trains in each block with any of their cars, derailed trains are off the tracks
+    fn occupancy(
+        trains: &BTreeMap<TrainID, TrainInstance>,
+        tracks: &BTreeMap<u32, TrackPiece>,
+    ) -> BTreeMap<BlockID, BTreeSet<TrainID>> {
+        let mut occupancy: BTreeMap<BlockID, BTreeSet<TrainID>> = BTreeMap::new();
+        for (id, train) in trains.iter().filter(|(_, train)| train.derailed.is_none()) {
+            for track_id in
+                std::iter::once(train.current_track).chain(train.trail.iter().map(|(id, _)| *id))
+            {
+                occupancy
+                    .entry(tracks.get(&track_id).unwrap().block)
+                    .or_default()
+                    .insert(*id);
+            }
+        }
+        occupancy
+    }
+
+    // another train in the block the train is about to run into, which it has to wait for
+    fn holder(
+        id: TrainID,
+        train: &TrainInstance,
+        tracks: &BTreeMap<u32, TrackPiece>,
+        junctions: &BTreeMap<JunctionID, Junction>,
+        occupancy: &BTreeMap<BlockID, BTreeSet<TrainID>>,
+    ) -> Option<TrainID> {
+        if train.derailed.is_some() {
+            return None;
         }
+        // no signal between tracks of the same block
+        let block = tracks.get(&train.current_track).unwrap().block;
+        let (track_id, _) = next_track(train.current_track, train.direction, tracks, junctions);
+        let next_block = tracks.get(&track_id).unwrap().block;
+        if next_block == block {
+            return None;
+        }
+        occupancy
+            .get(&next_block)?
+            .iter()
+            .copied()
+            .find(|other| *other != id)
+    }
+
+    // a block's signals stand at the ends of its tracks meeting tracks of other blocks
+    fn signal_packet(
+        block: BlockID,
+        aspect: SignalAspect,
+        tracks: &BTreeMap<u32, TrackPiece>,
+    ) -> ServerPacket {
+        let meets_other_block = |node: NodeID| {
+            tracks
+                .values()
+                .any(|track| track.block != block && (track.start == node || track.end == node))
+        };
+        let positions = tracks
+            .values()
+            .filter(|track| track.block == block)
+            .flat_map(|track| {
+                let offset = (SIGNAL_OFFSET / track.length).min(0.5f64);
+                [
+                    (track, track.start, offset),
+                    (track, track.end, 1f64 - offset),
+                ]
+            })
+            .filter(|(_, node, _)| meets_other_block(*node))
+            .map(|(track, _, progress)| track.position(progress))
+            .collect();
+        ServerPacket::PacketSIGNAL(block, aspect, positions)
+    }
+
+    println!("Server Started");
+
+    let mut tracks: BTreeMap<u32, TrackPiece> = layout
+        .tracks
+        .into_iter()
+        .map(|track| (track.id, track.into()))
+        .collect();
+
+    let mut junctions: BTreeMap<JunctionID, Junction> = layout
+        .junctions
+        .into_iter()
+        .map(|junction| (junction.id, junction.into()))
+        .collect();
+
+    let mut stations: BTreeMap<StationID, Station> = layout
+        .stations
+        .into_iter()
+        .map(|station| (station.id, station.into()))
+        .collect();
+    place_stations(&mut tracks, &stations);
 
-        // update train after a ceratin duration of movement, return true when train has switched to another track
-        fn move_with_time(
+    // where the screens placed by the server are and their scale, by screen name
+    let mut placements: BTreeMap<String, (Coord, f64)> = layout
+        .screens
+        .into_iter()
+        .map(|screen| (screen.name, (screen.offset, screen.scale)))
+        .collect();
+
+    let mut trains: BTreeMap<TrainID, TrainInstance> = layout
+        .trains
+        .into_iter()
+        .map(|train| (train.id, TrainInstance::from_layout(train, &tracks)))
+        .collect();
+
+    let mut events = EventQueue {
+        events: BTreeMultiMap::new(),
+    };
+    let now = tokio::time::Instant::now();
+    for id in trains.keys().copied().collect::<Vec<_>>() {
+        reschedule(id, now, &mut trains, &tracks, &junctions, &mut events);
+    }
+
+    valid_id_tx.send(trains.keys().copied().collect()).unwrap();
+
+    let mut viewers: BTreeMap<u32, Viewer> = BTreeMap::new();
+    let mut next_viewer_serial = 0u32;
+    let (packet_tx, mut packet_rx) = mpsc::channel::<(u32, ClientPacket)>(32);
+
+    // a connected viewer and what it has been sent so far
+    struct Viewer {
+        channel: mpsc::Sender<ServerPacket>,
+        screen: Option<Screen>, // until it reports its viewport it's sent everything
+        tracks: BTreeSet<TrackID>, // in the track list it was last sent
+        trains: BTreeSet<TrainID>, // last sent while in view
+        placed: bool,           // it has been told where the server placed its screen
+    }
+
+    impl Viewer {
+        fn sees(&self, track: &TrackPiece) -> bool {
+            match &self.screen {
+                Some(screen) => screen.sees(track.bounds),
+                None => true,
+            }
+        }
+
+        fn visible_tracks(&self, tracks: &BTreeMap<u32, TrackPiece>) -> BTreeSet<TrackID> {
+            tracks
+                .iter()
+                .filter(|(_, track)| self.sees(track))
+                .map(|(id, _)| *id)
+                .collect()
+        }
+
+        // any of its cars is on a track in view, derailed trains count as on the track they left
+        fn sees_train(&self, train: &TrainInstance, tracks: &BTreeMap<u32, TrackPiece>) -> bool {
+            std::iter::once(train.current_track)
+                .chain(train.trail.iter().map(|(id, _)| *id))
+                .any(|id| self.sees(tracks.get(&id).unwrap()))
+        }
+
+        // shake the screen for something happening to a train at position going at speed
+        async fn vibrate(&self, pattern: VibrationPattern, position: Coord, speed: f64) {
+            let vibration = match &self.screen {
+                Some(screen) => screen.vibration(pattern, position, speed),
+                None => None,
+            };
+            let (intensity, duration) = match vibration {
+                Some(vibration) => vibration,
+                None => return,
+            };
+            let _ = self
+                .channel
+                .send(ServerPacket::PacketVIBRATE(pattern, intensity, duration))
+                .await;
+        }
+
+        // the viewer moves its screen there and reports the new viewport
+        async fn place(&mut self, placements: &BTreeMap<String, (Coord, f64)>) {
+            let placement = self
+                .screen
+                .as_ref()
+                .and_then(|screen| placements.get(screen.name.as_ref()?));
+            if let Some((offset, scale)) = placement {
+                let _ = self
+                    .channel
+                    .send(ServerPacket::PacketPLACEMENT(*offset, *scale))
+                    .await;
+                self.placed = true;
+            }
+        }
+
+        // the track list replaces what the viewer has, so junctions are sent again right after it
+        async fn send_tracks(
             &mut self,
-            duration: Duration,
             tracks: &BTreeMap<u32, TrackPiece>,
-        ) -> bool {
-            let train = self;
-            let mut flag = false;
-            let mut move_distance = duration.as_secs_f64() * train.properties.speed;
-
-            loop {
-                let required_distance = match train.direction {
-                    Direction::Forward => 1f64 - train.progress,
-                    Direction::Backward => train.progress,
-                } * tracks.get(&train.current_track).unwrap().length;
-
-                if required_distance <= move_distance {
-                    move_distance -= required_distance;
-                    match train.direction {
-                        Direction::Forward => {
-                            train.current_track += 1;
-                            if train.current_track >= tracks.len() as u32 {
-                                train.current_track = 0;
-                            }
-                        }
-                        Direction::Backward => {
-                            if train.current_track == 0  {
-                                train.current_track = tracks.len() as u32;
-                            }
-                            train.current_track -= 1;
-                        },
-                    }
+            junctions: &BTreeMap<JunctionID, Junction>,
+        ) {
+            self.tracks = self.visible_tracks(tracks);
+            let _ = self.channel.send(track_packet(tracks, &self.tracks)).await;
+            for (id, junction) in junctions.iter() {
+                let _ = self.channel.send(junction.to_packet(*id)).await;
+            }
+        }
 
-                    train.progress = match train.direction {
-                        Direction::Forward => 0f64,
-                        Direction::Backward => 1f64,
-                    };
-                    flag = true;
-                } else {
-                    train.progress +=
-                        move_distance / tracks.get(&train.current_track).unwrap().length * match train.direction {
-                            Direction::Forward => 1f64,
-                            Direction::Backward => -1f64,
-                        };
-                    break;
-                }
+        // the train while it's in view, and once more as it leaves so it isn't left standing where it was last seen
+        async fn send_train(
+            &mut self,
+            id: TrainID,
+            train: &TrainInstance,
+            tracks: &BTreeMap<u32, TrackPiece>,
+        ) {
+            let sees = self.sees_train(train, tracks);
+            if sees || self.trains.contains(&id) {
+                let _ = self.channel.send(train.to_packet(id, tracks)).await;
             }
-            return flag;
+            // a train rolling onto the screen, or into view as the screen pans, is felt as strongly as where its
+            // track runs closest to the middle of the screen, standing trains aren't felt
+            let rolling_in = sees && !self.trains.contains(&id) && train.derailed.is_none();
+            if let (true, Some(screen)) = (rolling_in, &self.screen) {
+                let middle = screen.offset + screen.size * 0.5f64;
+                let track = tracks.get(&train.current_track).unwrap();
+                let position = track.path.point(track.path.closest_t(middle));
+                let speed = train.speed_at(tokio::time::Instant::now(), tracks);
+                self.vibrate(VibrationPattern::Rumble, position, speed)
+                    .await;
+            }
+            match sees {
+                true => self.trains.insert(id),
+                false => self.trains.remove(&id),
+            };
         }
+    }
 
-        fn to_packet(&self, id: u32, tracks: &BTreeMap<u32, TrackPiece>) -> ServerPacket {
-            ServerPacket::PacketTRAIN(
-                id,
-                self.current_track,
-                self.progress,
-                Duration::from_secs_f64(
-                    tracks.get(&self.current_track).unwrap().length / self.properties.speed,
-                ),
-                self.direction,
-                match self.direction {
-                    Direction::Forward => self.properties.image_forward.clone(),
-                    Direction::Backward => self.properties.image_backward.clone(),
-                },
+    async fn broadcast(viewers: &BTreeMap<u32, Viewer>, packet: ServerPacket) {
+        for (_, viewer) in viewers.iter() {
+            let _ = viewer.channel.send(packet.clone()).await;
+        }
+    }
+
+    // only to the viewers that see the train or just saw it
+    async fn broadcast_train(
+        viewers: &mut BTreeMap<u32, Viewer>,
+        id: TrainID,
+        train: &TrainInstance,
+        tracks: &BTreeMap<u32, TrackPiece>,
+    ) {
+        for viewer in viewers.values_mut() {
+            viewer.send_train(id, train, tracks).await;
+        }
+    }
+
+    // every screen around position feels it
+    async fn vibrate_near(
+        viewers: &BTreeMap<u32, Viewer>,
+        pattern: VibrationPattern,
+        position: Coord,
+        speed: f64,
+    ) {
+        for viewer in viewers.values() {
+            viewer.vibrate(pattern, position, speed).await;
+        }
+    }
+
+    // the screens around a train that has just flown off its track feel the crash
+    async fn vibrate_derailment(viewers: &BTreeMap<u32, Viewer>, train: &TrainInstance) {
+        if let Some(derailment) = &train.derailed {
+            vibrate_near(
+                viewers,
+                VibrationPattern::Crash,
+                derailment.position,
+                derailment.velocity.length(),
             )
+            .await;
         }
     }
 
-    println!("Server Started");
+    fn forget_closed_viewers(viewers: &mut BTreeMap<u32, Viewer>) {
+        viewers.retain(|_, viewer| !viewer.channel.is_closed());
+    }
 
-    let mut trains = vec![
-        TrainInstance {
-            properties: TrainProperties {
-                speed: 500f64,
-                image_forward: "train_right_debug.png".into(),
-                image_backward: "train_left_debug.png".into(),
-            },
-            current_track: 0,
-            progress: 0.0,
-            direction: Direction::Forward,
-        },
-        TrainInstance {
-            properties: TrainProperties {
-                speed: 250f64,
-                image_forward: "train_right_debug.png".into(),
-                image_backward: "train_left_debug.png".into(),
-            },
-            current_track: 0,
-            progress: 0.0,
-            direction: Direction::Backward,
-        },
-        TrainInstance {
-            properties: TrainProperties {
-                speed: 250f64,
-                image_forward: "train2_right.png".into(),
-                image_backward: "train2_left.png".into(),
-            },
-            current_track: 0,
-            progress: 0.0,
-            direction: Direction::Forward,
-        },
-    ];
-
-    let valid_train_id = (0..trains.len() as u32).collect();
-    valid_id_tx.send(valid_train_id).unwrap();
-
-    let tracks = {
-        let tracks_vec = vec![
-            // 1
-            TrackPiece {
-                path: Bezier::Bezier4(
-                    Coord(2000f64, 100f64),
-                    Coord(2200f64, 400f64),
-                    Coord(2900f64, 200f64),
-                    Coord(2800f64, 500f64),
-                ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            //2
-            TrackPiece {
-                path: Bezier::Bezier4(
-                    Coord(2800f64, 500f64),
-                    Coord(2400f64, 300f64),
-                    Coord(2400f64, 550f64),
-                    Coord(2200f64, 550f64),
-                ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 3
-            TrackPiece {
-                path: Bezier::Bezier4(
-                    Coord(2200f64, 550f64),
-                    Coord(2000f64, 550f64),
-                    Coord(2100f64, 450f64),
-                    Coord(1800f64, 350f64),
-                ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 4
-            TrackPiece {
-                path: Bezier::Bezier2(Coord(1800f64, 350f64), Coord(1300f64, 400f64)),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 5
-            TrackPiece {
-                path: Bezier::Bezier3(
-                    Coord(1300f64, 400f64),
-                    Coord(1200f64, 550f64),
-                    Coord(1000f64, 400f64),
-                ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 6
-            TrackPiece {
-                path: Bezier::Bezier3(
-                    Coord(1000f64, 400f64),
-                    Coord(650f64, 300f64),
-                    Coord(300f64, 400f64),
-                ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 7
-            TrackPiece {
-                path: Bezier::Bezier4(
-                    Coord(300f64, 400f64),
-                    Coord(175f64, 550f64),
-                    Coord(-200f64, 550f64),
-                    Coord(-200f64, 300f64),
-                ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 8
-            TrackPiece {
-                path: Bezier::Bezier3(
-                    Coord(-200f64, 300f64),
-                    Coord(-445f64, 500f64),
-                    Coord(-1175f64, 550f64),
-                ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 9
-            TrackPiece {
-                path: Bezier::Bezier2(Coord(-1175f64, 550f64), Coord(-1500f64, 400f64)),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 10
-            TrackPiece {
-                path: Bezier::Bezier2(Coord(-1500f64, 400f64), Coord(-2150f64, 450f64)),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 11
-            TrackPiece {
-                path: Bezier::Bezier3(
-                    Coord(-2150f64, 450f64),
-                    Coord(-2600f64, 550f64),
-                    Coord(-2800f64, 100f64),
-                ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 12
-            TrackPiece {
-                path: Bezier::Bezier2(Coord(-2800f64, 100f64), Coord(-2100f64, 100f64)),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 13
-            TrackPiece {
-                path: Bezier::Bezier4(
-                    Coord(-2100f64, 100f64),
-                    Coord(-1900f64, 150f64),
-                    Coord(-2000f64, 300f64),
-                    Coord(-1800f64, 350f64),
-                ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 14
-            TrackPiece {
-                path: Bezier::Bezier4(
-                    Coord(-1800f64, 350f64),
-                    Coord(-1700f64, 350f64),
-                    Coord(-1700f64, 300f64),
-                    Coord(-1700f64, 100f64),
-                ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 15
-            TrackPiece {
-                path: Bezier::Bezier4(
-                    Coord(-1700f64, 100f64),
-                    Coord(-1500f64, 100f64),
-                    Coord(-1600f64, 300f64),
-                    Coord(-1200f64, 300f64),
-                ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 16
-            TrackPiece {
-                path: Bezier::Bezier4(
-                    Coord(-1200f64, 300f64),
-                    Coord(-1100f64, 300f64),
-                    Coord(-950f64, 200f64),
-                    Coord(-900f64, 100f64),
-                ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 17
-            TrackPiece {
-                path: Bezier::Bezier4(
-                    Coord(-900f64, 100f64),
-                    Coord(-800f64, 100f64),
-                    Coord(-700f64, 150f64),
-                    Coord(-400f64, 200f64),
-                ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 18
-            TrackPiece {
-                path: Bezier::Bezier4(
-                    Coord(-400f64, 200f64),
-                    Coord(0f64, 200f64),
-                    Coord(0f64, 50f64),
-                    Coord(400f64, 200f64),
-                ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 19
-            TrackPiece {
-                path: Bezier::Bezier2(Coord(400f64, 200f64), Coord(750f64, 200f64)),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 20
-            TrackPiece {
-                path: Bezier::Bezier3(
-                    Coord(750f64, 200f64),
-                    Coord(800f64, 300f64),
-                    Coord(900f64, 200f64),
-                ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 21
-            TrackPiece {
-                path: Bezier::Bezier4(
-                    Coord(900f64, 200f64),
-                    Coord(1100f64, 100f64),
-                    Coord(1100f64, 300f64),
-                    Coord(1300f64, 300f64),
-                ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 22
-            TrackPiece {
-                path: Bezier::Bezier2(Coord(1300f64, 300f64), Coord(1700f64, 200f64)),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-            // 23
-            TrackPiece {
-                path: Bezier::Bezier3(
-                    Coord(1700f64, 200f64),
-                    Coord(1900f64, 250f64),
-                    Coord(2000f64, 100f64),
+    // where the screens of connected viewers are and how big, by screen name or the serial of unnamed viewers
+    fn registered_screens(viewers: &BTreeMap<u32, Viewer>) -> BTreeMap<String, (Coord, Coord)> {
+        viewers
+            .iter()
+            .filter_map(|(serial, viewer)| {
+                let screen = viewer.screen.as_ref()?;
+                let name = match &screen.name {
+                    Some(name) => name.clone(),
+                    None => format!("viewer#{}", serial),
+                };
+                Some((name, (screen.offset, screen.size)))
+            })
+            .filter(|(_, (_, size))| size.0 > 0f64 && size.1 > 0f64)
+            .collect()
+    }
+
+    // every viewer gets the tracks it sees
+    async fn broadcast_tracks(
+        viewers: &mut BTreeMap<u32, Viewer>,
+        tracks: &BTreeMap<u32, TrackPiece>,
+        junctions: &BTreeMap<JunctionID, Junction>,
+    ) {
+        for viewer in viewers.values_mut() {
+            viewer.send_tracks(tracks, junctions).await;
+        }
+    }
+
+    // two trains touch at time, deal with it as the collision policy says
+    async fn collide(
+        collision_policy: CollisionPolicy,
+        (a, b): (TrainID, TrainID),
+        time: tokio::time::Instant,
+        trains: &mut BTreeMap<TrainID, TrainInstance>,
+        tracks: &BTreeMap<u32, TrackPiece>,
+        viewers: &BTreeMap<u32, Viewer>,
+    ) {
+        let positions_a = trains[&a].car_positions_at(time, tracks);
+        let positions_b = trains[&b].car_positions_at(time, tracks);
+        let (position_a, position_b) = closest_cars(&positions_a, &positions_b);
+        println!("Train#{} and train#{} collided!!!", a, b);
+        broadcast(
+            viewers,
+            ServerPacket::PacketCOLLISION(a, b, (position_a + position_b) * 0.5f64),
+        )
+        .await;
+
+        // a train whose own motion closes the gap turns back, when neither does alone both do
+        let later = time + Duration::from_secs_f64(COLLISION_PROBE);
+        let gap = |a: &[Coord], b: &[Coord]| {
+            let (position_a, position_b) = closest_cars(a, b);
+            position_a.distance(position_b)
+        };
+        let turns = match (
+            gap(&trains[&a].car_positions_at(later, tracks), &positions_b)
+                < position_a.distance(position_b),
+            gap(&positions_a, &trains[&b].car_positions_at(later, tracks))
+                < position_a.distance(position_b),
+        ) {
+            (false, false) => (true, true),
+            turns => turns,
+        };
+
+        for (id, turn) in [(a, turns.0), (b, turns.1)] {
+            let train = trains.get_mut(&id).unwrap();
+            train.catch_up(time, tracks);
+            match collision_policy {
+                CollisionPolicy::Derail => train.derail(DerailReason::Collision, tracks),
+                CollisionPolicy::Stop => (train.speed, train.target_speed) = (0f64, 0f64),
+                CollisionPolicy::Bounce => {
+                    if turn {
+                        train.turn_around(tracks);
+                    }
+                }
+            }
+        }
+    }
+
+    // set the junction ahead of every train heading for a destination to the side its route takes,
+    // telling whether any junction was moved
+    async fn steer_junctions(
+        trains: &BTreeMap<TrainID, TrainInstance>,
+        tracks: &BTreeMap<u32, TrackPiece>,
+        junctions: &mut BTreeMap<JunctionID, Junction>,
+        stations: &BTreeMap<StationID, Station>,
+        viewers: &BTreeMap<u32, Viewer>,
+    ) -> bool {
+        let now = tokio::time::Instant::now();
+        let mut moved = false;
+        for (id, train) in trains.iter().filter(|(_, train)| train.derailed.is_none()) {
+            let station = match train.destination {
+                Some(station_id) => &stations[&station_id],
+                None => continue,
+            };
+            let junction_id =
+                match junction_ahead(train.current_track, train.direction, tracks, junctions) {
+                    Some(junction_id) => junction_id,
+                    None => continue,
+                };
+            // a destination out of reach may come back into reach after turning around
+            let route = plan_route(
+                (
+                    train.current_track,
+                    train.direction,
+                    train.progress_at(now, tracks),
                 ),
-                color: "#66FFCC".into(),
-                thickness: 20f64,
-                length: 500f64,
-            },
-        ];
-        let mut tracks = BTreeMap::new();
-        for (i, track) in tracks_vec.into_iter().enumerate() {
-            tracks.insert(i as u32, track);
-        }
-        tracks
-    };
+                (station.track, station.progress),
+                tracks,
+                junctions,
+            );
+            let side = match route.as_deref() {
+                Some([(first, side), ..]) if *first == junction_id => *side,
+                _ => continue,
+            };
 
-    let mut viewer_channels: BTreeMap<u32, mpsc::Sender<ServerPacket>> = BTreeMap::new();
-    let mut next_viewer_serial = 0u32;
-    let (click_tx, mut click_rx) = mpsc::channel::<(TrainID, ClickModifier)>(32);
+            let junction = junctions.get_mut(&junction_id).unwrap();
+            if junction.side != side {
+                junction.side = side;
+                moved = true;
+                println!(
+                    "Junction#{} is moved to side {} for train#{}",
+                    junction_id, side as u8, id
+                );
+                broadcast(viewers, junction.to_packet(junction_id)).await;
+            }
+        }
+        moved
+    }
 
-    loop {
-        let wait_start = tokio::time::Instant::now();
+    // light signals by whether their block is taken and hold trains in front of taken blocks until they're free;
+    // of two trains waiting for each other's block one with a free way back turns back, or they'd wait forever;
+    // the last train to give way is likely caught between two others, so the other one goes first;
+    // tells whether that moved any train onto or off a track, so the signals need working out again
+    async fn update_signals(
+        trains: &mut BTreeMap<TrainID, TrainInstance>,
+        tracks: &BTreeMap<u32, TrackPiece>,
+        junctions: &BTreeMap<JunctionID, Junction>,
+        signals: &mut BTreeMap<BlockID, SignalAspect>,
+        gave_way: &mut Option<TrainID>,
+        events: &mut EventQueue,
+        viewers: &mut BTreeMap<u32, Viewer>,
+    ) -> bool {
+        let now = tokio::time::Instant::now();
+        let occupancy = occupancy(trains, tracks);
 
-        // calculate when will the next train reach the end of it's current track
-        let wait_time = trains
+        let blocks: BTreeSet<BlockID> = tracks.values().map(|track| track.block).collect();
+        signals.retain(|block, _| blocks.contains(block));
+        for block in blocks {
+            let aspect = match occupancy.contains_key(&block) {
+                true => SignalAspect::Red,
+                false => SignalAspect::Green,
+            };
+            if signals.insert(block, aspect) != Some(aspect) {
+                broadcast(viewers, signal_packet(block, aspect, tracks)).await;
+            }
+        }
+
+        let holders: BTreeMap<TrainID, TrainID> = trains
             .iter()
-            .map(|train| train.estimated_time_left(&tracks))
-            .min()
-            .unwrap();
+            .filter_map(|(id, train)| {
+                Some((*id, holder(*id, train, tracks, junctions, &occupancy)?))
+            })
+            .collect();
+        let free_way_back = |id: TrainID| {
+            let mut turned = trains[&id].clone();
+            turned.catch_up(now, tracks);
+            turned.turn_around(tracks);
+            holder(id, &turned, tracks, junctions, &occupancy).is_none()
+        };
+        let mut turning = BTreeSet::new();
+        for (id, holder) in holders.iter() {
+            if holders.get(holder) == Some(id) && id < holder {
+                let mut pair = [*id, *holder];
+                if *gave_way == Some(*id) {
+                    pair.reverse();
+                }
+                if let Some(turner) = pair.into_iter().find(|id| free_way_back(*id)) {
+                    println!(
+                        "Train#{} and train#{} are waiting for each other, train#{} turns back",
+                        id, holder, turner
+                    );
+                    turning.insert(turner);
+                    *gave_way = Some(turner);
+                }
+            }
+        }
+
+        let mut moved = !turning.is_empty();
+        for id in trains.keys().copied().collect::<Vec<_>>() {
+            let held = holders.contains_key(&id) && !turning.contains(&id);
+            let train = trains.get_mut(&id).unwrap();
+            if train.held == held && !turning.contains(&id) {
+                continue;
+            }
+
+            train.catch_up(now, tracks);
+            if turning.contains(&id) {
+                train.turn_around(tracks);
+            }
+            train.held = held;
+            // planned again for the new end speed
+            train.braking = None;
+            moved |= reschedule(id, now, trains, tracks, junctions, events);
+            broadcast_train(viewers, id, &trains[&id], tracks).await;
+        }
+        moved
+    }
+
+    fn snapshot(
+        tracks: &BTreeMap<u32, TrackPiece>,
+        junctions: &BTreeMap<JunctionID, Junction>,
+        trains: &BTreeMap<TrainID, TrainInstance>,
+        stations: &BTreeMap<StationID, Station>,
+        placements: &BTreeMap<String, (Coord, f64)>,
+    ) -> Layout {
+        let now = tokio::time::Instant::now();
+        Layout {
+            tracks: tracks
+                .iter()
+                .map(|(id, track)| TrackLayout {
+                    id: *id,
+                    path: track.path,
+                    color: track.color.clone(),
+                    thickness: track.thickness,
+                    start: track.start,
+                    end: track.end,
+                    block: (track.block != *id).then_some(track.block),
+                })
+                .collect(),
+            junctions: junctions
+                .iter()
+                .map(|(id, junction)| JunctionLayout {
+                    id: *id,
+                    node: junction.node,
+                    track: junction.track,
+                    sides: junction.sides,
+                    side: junction.side,
+                })
+                .collect(),
+            trains: trains
+                .iter()
+                .map(|(id, train)| TrainLayout {
+                    id: *id,
+                    speed: train.properties.speed,
+                    max_speed: train.properties.max_speed,
+                    acceleration: train.properties.acceleration,
+                    deceleration: train.properties.deceleration,
+                    length: train.properties.length,
+                    image_forward: train.properties.cars[0].image_forward.clone(),
+                    image_backward: train.properties.cars[0].image_backward.clone(),
+                    track: train.current_track,
+                    direction: train.direction,
+                    progress: train.progress_at(now, tracks),
+                    derailed: train.derailed.as_ref().map(|derailment| derailment.reason),
+                    cars: train
+                        .properties
+                        .cars
+                        .iter()
+                        .skip(1)
+                        .map(|car| CarLayout {
+                            image_forward: car.image_forward.clone(),
+                            image_backward: car.image_backward.clone(),
+                            spacing: car.spacing,
+                        })
+                        .collect(),
+                    timetable: train.properties.timetable.as_ref().map(|timetable| {
+                        TimetableLayout {
+                            period: timetable.period,
+                            stops: timetable
+                                .stops
+                                .iter()
+                                .map(|(station, departure)| StopLayout {
+                                    station: *station,
+                                    departure: *departure,
+                                })
+                                .collect(),
+                            next: train.next_stop,
+                        }
+                    }),
+                    destination: train.destination,
+                })
+                .collect(),
+            stations: stations
+                .iter()
+                .map(|(id, station)| StationLayout {
+                    id: *id,
+                    name: station.name.clone(),
+                    track: station.track,
+                    progress: station.progress,
+                    dwell: station.dwell,
+                })
+                .collect(),
+            screens: placements
+                .iter()
+                .map(|(name, (offset, scale))| ScreenLayout {
+                    name: name.clone(),
+                    offset: *offset,
+                    scale: *scale,
+                })
+                .collect(),
+        }
+    }
+
+    fn track_packet(
+        tracks: &BTreeMap<u32, TrackPiece>,
+        visible: &BTreeSet<TrackID>,
+    ) -> ServerPacket {
+        ServerPacket::PacketTRACK(
+            tracks
+                .iter()
+                .filter(|a| visible.contains(a.0))
+                .map(|a| {
+                    (
+                        *a.0,
+                        a.1.path,
+                        a.1.color.clone(),
+                        a.1.thickness,
+                        a.1.length,
+                        a.1.arc_table.clone(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    let mut signals: BTreeMap<BlockID, SignalAspect> = BTreeMap::new();
+    let mut gave_way = None;
+    // signals only change when a train comes onto or leaves a track, turns, derails, or the tracks change
+    let mut signals_stale = true;
+    // routes only change when a routed train comes onto another track or turns, is sent somewhere,
+    // or the tracks and junctions change under it
+    let mut routes_stale = true;
+
+    let mut generator = Generator::default();
+    let mut generated_for: Option<BTreeSet<String>> = None; // screens the tracks were laid over
+
+    loop {
+        forget_closed_viewers(&mut viewers);
+
+        // lay the tracks anew once screens come or go, keeping the last ones while no screen is around;
+        // a viewer sending its viewport again as it scrolls or resizes doesn't move the tracks under it
+        let screens = match config.generate_tracks {
+            true => registered_screens(&viewers),
+            false => BTreeMap::new(),
+        };
+        let names: BTreeSet<String> = screens.keys().cloned().collect();
+        if !screens.is_empty() && generated_for.as_ref() != Some(&names) {
+            println!("Laying tracks over {} screens...", screens.len());
+
+            let now = tokio::time::Instant::now();
+            for train in trains.values_mut() {
+                train.catch_up(now, &tracks);
+            }
+
+            let mut new_tracks: BTreeMap<u32, TrackPiece> = generator
+                .generate(&screens)
+                .into_iter()
+                .map(|track| (track.id, track.into()))
+                .collect();
+
+            // stations and trains on tracks that aren't kept go to the closest point of the new ones
+            let kept = kept_tracks(&tracks, &new_tracks);
+            for station in stations.values_mut() {
+                if station.relocate(&kept, &tracks, &new_tracks) {
+                    println!(
+                        "{} lost its track, moved to track#{}",
+                        station.name, station.track
+                    );
+                }
+            }
+            place_stations(&mut new_tracks, &stations);
+            for (id, train) in trains.iter_mut() {
+                if train.relocate(&kept, &tracks, &new_tracks) {
+                    println!(
+                        "Train#{} lost its track, moved to track#{}",
+                        id, train.current_track
+                    );
+                }
+            }
+
+            // the generated tracks run in one loop without junctions of their own, a junction only stays
+            // while the tracks it joins are kept, and with them the node they meet at
+            junctions.retain(|_, junction| {
+                kept.contains(&junction.track)
+                    && junction.sides.iter().all(|side| kept.contains(side))
+            });
+            tracks = new_tracks;
+            for id in trains.keys().copied().collect::<Vec<_>>() {
+                reschedule(id, now, &mut trains, &tracks, &junctions, &mut events);
+            }
+            broadcast_tracks(&mut viewers, &tracks, &junctions).await;
+            signals.clear(); // sent again by the signal update right below
+            signals_stale = true;
+            routes_stale = true;
+            for (id, train) in trains.iter() {
+                broadcast_train(&mut viewers, *id, train, &tracks).await;
+            }
+            generated_for = Some(names);
+        }
+
+        if routes_stale {
+            routes_stale = false;
+            signals_stale |=
+                steer_junctions(&trains, &tracks, &mut junctions, &stations, &viewers).await;
+        }
+        if signals_stale {
+            // trains turned back for a deadlock are looked at once more from where they are now
+            signals_stale = update_signals(
+                &mut trains,
+                &tracks,
+                &junctions,
+                &mut signals,
+                &mut gave_way,
+                &mut events,
+                &mut viewers,
+            )
+            .await;
+            routes_stale |= signals_stale;
+        }
+
+        // sleep until the next event, or until something else happens in an empty world
+        let wait = match events.next_time() {
+            Some(time) => tokio::time::sleep_until(time),
+            None => tokio::time::sleep(Duration::MAX),
+        };
 
-        let wait = tokio::time::sleep(wait_time);
         tokio::select! {
             biased;
 
             _ = wait => {
-                let wait_end = tokio::time::Instant::now();
-                for (i, train) in trains.iter_mut().enumerate() {
-                    if train.move_with_time(wait_end - wait_start, &tracks) {
-                        for (_, channel) in viewer_channels.iter() {
-                            channel.send(train.to_packet(i as u32, &tracks)).await;
+                // handling an event may plan others that are already due
+                while let Some((time, event)) = events.pop_due(tokio::time::Instant::now()) {
+                    match event {
+                        Event::Arrival(id, epoch) => {
+                            if trains.get(&id).is_none_or(|train| train.epoch != epoch) {
+                                continue;
+                            }
+                            // a train too fast to stop at a signal in time stops dead in front of it
+                            let overrun = trains[&id].target_progress(&tracks).1 == Target::TrackEnd
+                                && holder(id, &trains[&id], &tracks, &junctions, &occupancy(&trains, &tracks)).is_some();
+                            let train = trains.get_mut(&id).unwrap();
+                            train.catch_up(time, &tracks);
+                            let (from_track, from_direction) = (train.current_track, train.direction);
+                            if overrun {
+                                println!("Train#{} ran into a red signal!!!", id);
+                                (train.speed, train.held, train.braking) = (0f64, true, Some(0f64));
+                            } else {
+                                train.arrive(&tracks, &junctions, &stations);
+                                if let Some((station_id, departure)) = train.dwelling {
+                                    println!(
+                                        "Train#{} is at {}, leaving in {:.1}s",
+                                        id,
+                                        stations[&station_id].name,
+                                        departure.saturating_duration_since(time).as_secs_f64()
+                                    );
+                                }
+                            }
+                            reschedule(id, time, &mut trains, &tracks, &junctions, &mut events);
+                            signals_stale = true;
+                            // only a train sent somewhere needs its route planned again, once it's on another track
+                            let train = &trains[&id];
+                            routes_stale |= train.destination.is_some() && train.current_track != from_track;
+                            broadcast_train(&mut viewers, id, &trains[&id], &tracks).await;
+                            vibrate_derailment(&viewers, &trains[&id]).await;
+
+                            // the screen under a junction the train has just run over feels the bump
+                            let train = &trains[&id];
+                            if train.current_track != from_track {
+                                let track = tracks.get(&from_track).unwrap();
+                                let (node, progress) = match from_direction {
+                                    Direction::Forward => (track.end, 1f64),
+                                    Direction::Backward => (track.start, 0f64),
+                                };
+                                if junctions.values().any(|junction| junction.node == node) {
+                                    vibrate_near(&viewers, VibrationPattern::Bump, track.position(progress), train.speed).await;
+                                }
+                            }
+                        }
+                        Event::Brake(id, epoch) => {
+                            let train = match trains.get_mut(&id) {
+                                Some(train) if train.epoch == epoch => train,
+                                _ => continue,
+                            };
+                            train.catch_up(time, &tracks);
+                            train.braking = train.end_speed(&tracks, &junctions);
+                            signals_stale |= reschedule(id, time, &mut trains, &tracks, &junctions, &mut events);
+                            broadcast_train(&mut viewers, id, &trains[&id], &tracks).await;
+                        }
+                        Event::Departure(id, epoch) => {
+                            let train = match trains.get_mut(&id) {
+                                Some(train) if train.epoch == epoch => train,
+                                _ => continue,
+                            };
+                            train.catch_up(time, &tracks);
+                            train.depart();
+                            routes_stale = true; // off to the next stop
+                            signals_stale |= reschedule(id, time, &mut trains, &tracks, &junctions, &mut events);
+                            broadcast_train(&mut viewers, id, &trains[&id], &tracks).await;
+                        }
+                        Event::Collision((a, epoch_a), (b, epoch_b)) => {
+                            let current = |id, epoch| trains.get(&id).is_some_and(|train| train.epoch == epoch);
+                            if !current(a, epoch_a) || !current(b, epoch_b) {
+                                continue;
+                            }
+                            collide(config.collision_policy, (a, b), time, &mut trains, &tracks, &viewers).await;
+                            signals_stale = true;
+                            for id in [a, b] {
+                                reschedule(id, time, &mut trains, &tracks, &junctions, &mut events);
+                                broadcast_train(&mut viewers, id, &trains[&id], &tracks).await;
+                                vibrate_derailment(&viewers, &trains[&id]).await;
+                            }
                         }
                     }
                 }
             }
-            clicked = click_rx.recv() => {
-                let (clicked, modifier) = clicked.unwrap();
-                println!("Train#{} is clicked, \n {:?}", clicked, modifier);
+            packet = packet_rx.recv() => {
+                let now = tokio::time::Instant::now();
+                let (serial, packet) = packet.unwrap();
+                match packet {
+                    ClientPacket::PacketCLICK(clicked, modifier) => {
+                        println!("Train#{} is clicked, \n {:?}", clicked, modifier);
 
-                let wait_end = tokio::time::Instant::now();
-                for (i, train) in trains.iter_mut().enumerate() {
-                    if i == clicked as usize {
-                        if train.move_with_time(wait_end - wait_start + Duration::from_secs(3), &tracks) {
-                            for (_, channel) in viewer_channels.iter() {
-                                channel.send(train.to_packet(i as u32, &tracks)).await;
+                        let train = match trains.get_mut(&clicked) {
+                            Some(train) => train,
+                            None => continue,
+                        };
+                        train.catch_up(now, &tracks);
+
+                        let action = config
+                            .click_actions
+                            .iter()
+                            .rev()
+                            .find(|binding| binding.modifier == modifier)
+                            .map(|binding| binding.action);
+                        let derailed = train.derailed.is_some();
+                        // turning round, coming off or back on the rails and switching the junction ahead
+                        // all change which blocks trains are in or heading for
+                        signals_stale |= matches!(
+                            (derailed, action),
+                            (true, Some(ClickAction::Rerail))
+                                | (false, Some(ClickAction::Reverse | ClickAction::Derail | ClickAction::Junction))
+                        );
+                        routes_stale |= matches!(
+                            (derailed, action),
+                            (true, Some(ClickAction::Rerail)) | (false, Some(ClickAction::Reverse | ClickAction::Junction))
+                        );
+                        match (derailed, action) {
+                            (_, None) => {
+                                println!("Clicks with {:?} aren't bound to anything", modifier);
+                                continue;
+                            }
+                            (true, Some(ClickAction::Rerail)) => train.rerail(),
+                            (true, Some(action)) => {
+                                println!("Train#{} is derailed, it has to be put back on track before {:?}", clicked, action);
+                                continue;
+                            }
+                            (false, Some(ClickAction::Rerail)) => {
+                                println!("Train#{} is on its track already", clicked);
+                                continue;
+                            }
+                            // up to its max speed, which may be too fast for the next curve
+                            (false, Some(ClickAction::Boost)) => {
+                                train.target_speed = (train.target_speed + CLICK_BOOST).min(train.properties.max_speed);
+                            }
+                            (false, Some(ClickAction::Reverse)) => train.turn_around(&tracks),
+                            (false, Some(ClickAction::StopStart)) => {
+                                train.target_speed = match train.target_speed > 0f64 {
+                                    true => 0f64,
+                                    false => train.properties.speed,
+                                };
+                            }
+                            (false, Some(ClickAction::Derail)) => {
+                                println!("Train#{} is derailed by a click!!!", clicked);
+                                train.derail(DerailReason::Forced, &tracks);
+                            }
+                            // the train itself goes on as before
+                            (false, Some(ClickAction::Junction)) => {
+                                match next_junction(train.current_track, train.direction, &tracks, &junctions) {
+                                    Some(junction_id) => {
+                                        let junction = junctions.get_mut(&junction_id).unwrap();
+                                        junction.side = !junction.side;
+                                        println!("Junction#{} is moved to side {}", junction_id, junction.side as u8);
+                                        broadcast(&viewers, junction.to_packet(junction_id)).await;
+                                    }
+                                    None => println!("Train#{} has no junction ahead", clicked),
+                                }
+                                continue;
                             }
                         }
-                    } else
-                    if train.move_with_time(wait_end - wait_start, &tracks) {
-                        for (_, channel) in viewer_channels.iter() {
-                            channel.send(train.to_packet(i as u32, &tracks)).await;
+                        signals_stale |= reschedule(clicked, now, &mut trains, &tracks, &junctions, &mut events);
+                        broadcast_train(&mut viewers, clicked, &trains[&clicked], &tracks).await;
+                        vibrate_derailment(&viewers, &trains[&clicked]).await;
+                    }
+                    ClientPacket::PacketNEWNODE(junction_id, track_id, start, end) => {
+                        println!("New track#{} from junction#{}, {} -> {}", track_id, junction_id, start, end);
+
+                        if tracks.contains_key(&track_id) {
+                            println!("Track#{} already exists, ignoring new node", track_id);
+                            continue;
+                        }
+
+                        // the track leaves from the junction's node, whatever start says, and ends on the node
+                        // under end or a new node when there's none
+                        let junction = match junctions.get(&junction_id) {
+                            Some(junction) => junction,
+                            None => {
+                                println!("Junction#{} doesn't exist, ignoring new node", junction_id);
+                                continue;
+                            }
+                        };
+                        let start_node = junction.node;
+                        let end_node = node_at(end, &tracks).unwrap_or_else(|| {
+                            tracks
+                                .values()
+                                .flat_map(|track| [track.start, track.end])
+                                .max()
+                                .map_or(0, |node| node + 1)
+                        });
+
+                        // both ends snapping to one node would make a track going nowhere
+                        let path = Bezier::Bezier2(
+                            node_position(start_node, &tracks).unwrap_or(start),
+                            node_position(end_node, &tracks).unwrap_or(end),
+                        );
+                        if start_node == end_node || path.length() < MIN_TRACK_LENGTH {
+                            println!("Track#{} would start where it ends, ignoring new node", track_id);
+                            continue;
+                        }
+
+                        tracks.insert(
+                            track_id,
+                            TrackPiece::new(
+                                path,
+                                TRACK_COLOR.into(),
+                                TRACK_THICKNESS,
+                                start_node,
+                                end_node,
+                                track_id,
+                            ),
+                        );
+                        // the new track takes the side the junction isn't switched to, so MOVEJUNCTION sends
+                        // trains onto it
+                        let junction = junctions.get_mut(&junction_id).unwrap();
+                        junction.sides[(!junction.side) as usize] = track_id;
+                        broadcast_tracks(&mut viewers, &tracks, &junctions).await;
+                        signals.clear(); // sent again by the next signal update
+                        signals_stale = true;
+                        routes_stale = true;
+                    }
+                    ClientPacket::PacketNEWTRAIN(position, track_id) => {
+                        println!("New train on track#{} near {}", track_id, position);
+
+                        let track = match tracks.get(&track_id) {
+                            Some(track) => track,
+                            None => {
+                                println!("Track#{} doesn't exist, ignoring new train", track_id);
+                                continue;
+                            }
+                        };
+
+                        let train_id = trains.keys().next_back().map_or(0, |id| id + 1);
+                        let progress = track.progress_at(track.path.closest_t(position));
+                        let mut train =
+                            TrainInstance::from_layout(TrainLayout::new(train_id, track_id, progress), &tracks);
+                        train.speed = 0f64; // pulling away from where it's put
+                        trains.insert(train_id, train);
+                        reschedule(train_id, now, &mut trains, &tracks, &junctions, &mut events);
+                        signals_stale = true;
+                        broadcast_train(&mut viewers, train_id, &trains[&train_id], &tracks).await;
+                        valid_id_tx.send_replace(trains.keys().copied().collect());
+                    }
+                    ClientPacket::PacketMOVEJUNCTION(junction_id, side) => {
+                        println!("Junction#{} is moved to side {}", junction_id, side as u8);
+
+                        let junction = match junctions.get_mut(&junction_id) {
+                            Some(junction) => junction,
+                            None => {
+                                println!("Junction#{} doesn't exist, ignoring junction move", junction_id);
+                                continue;
+                            }
+                        };
+
+                        junction.side = side;
+                        signals_stale = true;
+                        routes_stale = true;
+                        broadcast(&viewers, junction.to_packet(junction_id)).await;
+                    }
+                    ClientPacket::PacketVIEWPORT(offset, size, name) => {
+                        println!(
+                            "Viewer#{} shows {} sized {}{}",
+                            serial,
+                            offset,
+                            size,
+                            name.as_ref().map_or(String::new(), |name| format!(" as {}", name))
+                        );
+                        let viewer = match viewers.get_mut(&serial) {
+                            Some(viewer) => viewer,
+                            None => continue,
+                        };
+                        viewer.screen = Some(Screen { name, offset, size });
+                        if !viewer.placed {
+                            viewer.place(&placements).await;
+                        }
+
+                        // a viewer that pans gets the tracks and trains it can see now
+                        if viewer.visible_tracks(&tracks) != viewer.tracks {
+                            viewer.send_tracks(&tracks, &junctions).await;
+                            for (block, aspect) in signals.iter() {
+                                let _ = viewer.channel.send(signal_packet(*block, *aspect, &tracks)).await;
+                            }
+                        }
+                        for (id, train) in trains.iter() {
+                            viewer.send_train(*id, train, &tracks).await;
                         }
                     }
                 }
             }
 
-            request_result = view_request_rx.recv() => {
+            // closed channels (the web server is gone while shutting down) just disable their branch
+            Some(response_tx) = view_request_rx.recv() => {
                 // received new view request
-                let response_tx = request_result.unwrap();
                 let (notify_tx, notify_rx) = mpsc::channel(4);
 
-                response_tx.send((notify_rx, click_tx.clone())).unwrap();
-                notify_tx.send(ServerPacket::PacketTRACK(tracks.iter().map(
-                    |a| (*a.0, a.1.path, a.1.color.clone(), a.1.thickness)
-                ).collect())).await.unwrap();
+                response_tx.send((next_viewer_serial, notify_rx, packet_tx.clone())).unwrap();
+                let mut viewer = Viewer {
+                    channel: notify_tx,
+                    screen: None,
+                    tracks: BTreeSet::new(),
+                    trains: BTreeSet::new(),
+                    placed: false,
+                };
+                viewer.send_tracks(&tracks, &junctions).await;
+                for (block, aspect) in signals.iter() {
+                    let _ = viewer.channel.send(signal_packet(*block, *aspect, &tracks)).await;
+                }
+                for (id, train) in trains.iter() {
+                    viewer.send_train(*id, train, &tracks).await;
+                }
+                viewers.insert(next_viewer_serial, viewer);
+                next_viewer_serial += 1;
+            }
+
+            Some(layout) = admin_rx.reload_rx.recv() => {
+                if layout.tracks.is_empty() && !trains.is_empty() {
+                    println!("Reloaded layout has no tracks for the trains to stay on, keeping the current one");
+                    continue;
+                }
+                println!("Reloading layout...");
+
+                let now = tokio::time::Instant::now();
+                for train in trains.values_mut() {
+                    train.catch_up(now, &tracks);
+                }
 
-                let wait_end = tokio::time::Instant::now();
-                for (i, train) in trains.iter_mut().enumerate() {
-                    if train.move_with_time(wait_end - wait_start, &tracks) {
-                        for (_, channel) in viewer_channels.iter() {
-                            channel.send(train.to_packet(i as u32, &tracks)).await;
+                let mut new_tracks: BTreeMap<u32, TrackPiece> = layout
+                    .tracks
+                    .into_iter()
+                    .map(|track| (track.id, track.into()))
+                    .collect();
+                stations = layout
+                    .stations
+                    .into_iter()
+                    .map(|station| (station.id, station.into()))
+                    .collect();
+                place_stations(&mut new_tracks, &stations);
+                for train in trains.values_mut() {
+                    train.destination = train.destination.filter(|station_id| stations.contains_key(station_id));
+                }
+
+                // junctions that are still around keep the side they were switched to
+                let mut new_junctions: BTreeMap<JunctionID, Junction> = layout
+                    .junctions
+                    .into_iter()
+                    .map(|junction| (junction.id, junction.into()))
+                    .collect();
+                for (id, junction) in new_junctions.iter_mut() {
+                    if let Some(old_junction) = junctions.get(id) {
+                        junction.side = old_junction.side;
+                    }
+                }
+
+                // trains on tracks removed or laid differently go to the closest point of the new ones
+                let kept = kept_tracks(&tracks, &new_tracks);
+                for (id, train) in trains.iter_mut() {
+                    if train.relocate(&kept, &tracks, &new_tracks) {
+                        println!("Train#{} lost its track, moved to track#{}", id, train.current_track);
+                    }
+                }
+
+                // trains keep running where they are, only picking up new properties
+                for train in layout.trains {
+                    match trains.get_mut(&train.id) {
+                        Some(running) => {
+                            // a boosted train stays as much faster than its new cruising speed
+                            running.target_speed = (running.target_speed + train.speed - running.properties.speed)
+                                .clamp(0f64, train.max_speed);
+                            running.properties = train_properties(&train);
+                            // a new timetable starts over, the one it had goes on from the same stop
+                            running.next_stop = match &train.timetable {
+                                Some(timetable) if timetable.stops.len() > running.next_stop => running.next_stop,
+                                _ => 0,
+                            };
+                        }
+                        None => {
+                            trains.insert(train.id, TrainInstance::from_layout(train, &new_tracks));
                         }
                     }
-                    notify_tx.send(train.to_packet(i as u32, &tracks)).await;
                 }
-                viewer_channels.insert(next_viewer_serial, notify_tx);
-                next_viewer_serial += 1;
+                valid_id_tx.send_replace(trains.keys().copied().collect());
+
+                tracks = new_tracks;
+                junctions = new_junctions;
+                // every train's plans depend on the old tracks
+                for id in trains.keys().copied().collect::<Vec<_>>() {
+                    reschedule(id, now, &mut trains, &tracks, &junctions, &mut events);
+                }
+                broadcast_tracks(&mut viewers, &tracks, &junctions).await;
+                signals.clear(); // sent again by the next signal update
+                signals_stale = true;
+                routes_stale = true;
+                for (id, train) in trains.iter() {
+                    broadcast_train(&mut viewers, *id, train, &tracks).await;
+                }
+
+                // the layout file's tracks make way for generated ones again
+                generated_for = None;
+
+                // screens left out of the new layout stay where they are
+                placements = layout
+                    .screens
+                    .into_iter()
+                    .map(|screen| (screen.name, (screen.offset, screen.scale)))
+                    .collect();
+                for viewer in viewers.values_mut() {
+                    viewer.place(&placements).await;
+                }
+            }
+
+            Some(request) = admin_rx.snapshot_rx.recv() => {
+                let _ = request.send(snapshot(&tracks, &junctions, &trains, &stations, &placements));
+            }
+
+            Some((train_id, station_id)) = admin_rx.send_rx.recv() => {
+                let (train, station) = match (trains.get_mut(&train_id), stations.get(&station_id)) {
+                    (Some(train), Some(station)) => (train, station),
+                    _ => {
+                        println!("Received send request but there's no such train or station");
+                        continue;
+                    }
+                };
+                let now = tokio::time::Instant::now();
+                train.catch_up(now, &tracks);
+                let route = plan_route(
+                    (train.current_track, train.direction, train.progress),
+                    (station.track, station.progress),
+                    &tracks,
+                    &junctions,
+                );
+                if route.is_none() {
+                    println!("Train#{} has no way to {}", train_id, station.name);
+                    continue;
+                }
+                println!("Train#{} is sent to {}", train_id, station.name);
+                // it stops at the destination even if it'd pass it by otherwise
+                train.destination = Some(station_id);
+                train.braking = None;
+                routes_stale = true;
+                signals_stale |= reschedule(train_id, now, &mut trains, &tracks, &junctions, &mut events);
+                broadcast_train(&mut viewers, train_id, &trains[&train_id], &tracks).await;
+            }
+
+            Some(request) = admin_rx.screens_rx.recv() => {
+                forget_closed_viewers(&mut viewers);
+                let _ = request.send(
+                    viewers
+                        .iter()
+                        .filter_map(|(serial, viewer)| Some((*serial, viewer.screen.clone()?)))
+                        .collect(),
+                );
+            }
+
+            Some((name, offset, scale)) = admin_rx.place_rx.recv() => {
+                println!("Screen {} is placed at {} zoomed {}x", name, offset, scale);
+                placements.insert(name.clone(), (offset, scale));
+                for viewer in viewers.values_mut() {
+                    if viewer.screen.as_ref().is_some_and(|screen| screen.name.as_ref() == Some(&name)) {
+                        viewer.place(&placements).await;
+                    }
+                }
             }
 
-            _ = derail_rx.recv() => {
-                println!("RECEIVED DERAIL REQUEST!!!");
-                break;
+            Some(request) = admin_rx.arrivals_rx.recv() => {
+                let _ = request.send(upcoming_arrivals(&trains, &tracks, &junctions, &stations));
+            }
+
+            Some(train_id) = admin_rx.derail_rx.recv() => {
+                // without a train given, any running train will do
+                let train_id = train_id.or_else(|| {
+                    trains
+                        .iter()
+                        .filter(|(_, train)| train.derailed.is_none())
+                        .map(|(id, _)| *id)
+                        .choose(&mut rand::thread_rng())
+                });
+                match train_id.filter(|id| trains.get(id).is_some_and(|train| train.derailed.is_none())) {
+                    Some(id) => {
+                        println!("Train#{} is forced to derail!!!", id);
+                        let now = tokio::time::Instant::now();
+                        let train = trains.get_mut(&id).unwrap();
+                        train.catch_up(now, &tracks);
+                        train.derail(DerailReason::Forced, &tracks);
+                        reschedule(id, now, &mut trains, &tracks, &junctions, &mut events);
+                        signals_stale = true;
+                        broadcast_train(&mut viewers, id, &trains[&id], &tracks).await;
+                        vibrate_derailment(&viewers, &trains[&id]).await;
+                    }
+                    None => println!("Received derail request but there's no such running train"),
+                }
+            }
+
+            Some(train_id) = admin_rx.rerail_rx.recv() => {
+                // without a train given, every derailed train is put back
+                let now = tokio::time::Instant::now();
+                let derailed: Vec<TrainID> = trains
+                    .iter()
+                    .filter(|(id, train)| train.derailed.is_some() && train_id.is_none_or(|train_id| train_id == **id))
+                    .map(|(id, _)| *id)
+                    .collect();
+                for id in derailed {
+                    println!("Train#{} is back on track#{}", id, trains[&id].current_track);
+                    trains.get_mut(&id).unwrap().rerail();
+                    reschedule(id, now, &mut trains, &tracks, &junctions, &mut events);
+                    signals_stale = true;
+                    routes_stale = true;
+                    broadcast_train(&mut viewers, id, &trains[&id], &tracks).await;
+                }
             }
         }
     }
@@ -604,13 +1770,104 @@ async fn train_master(
 
 #[tokio::main]
 async fn main() {
+    let config = Config::parse();
+
+    // pick up where the last run left off, a broken snapshot only costs the trains their positions,
+    // and a layout file edited since the snapshot was taken wins over it
+    let layout_edited = edited_after(&config.layout, &config.state);
+    let snapshot = match config.state.exists() {
+        true if layout_edited => {
+            println!(
+                "!!! {} was edited after the snapshot in {} was taken, starting from the layout and leaving the snapshot behind !!!",
+                config.layout.display(),
+                config.state.display()
+            );
+            None
+        }
+        true => match Layout::load(&config.state) {
+            Ok(snapshot) => {
+                println!("Restored world from {}", config.state.display());
+                Some(snapshot)
+            }
+            Err(err) => {
+                println!(
+                    "Failed to restore snapshot, starting from the layout:\n{}",
+                    err
+                );
+                None
+            }
+        },
+        false => None,
+    };
+
+    let layout = match snapshot {
+        Some(snapshot) => snapshot,
+        None => match Layout::load(&config.layout) {
+            Ok(layout) => layout,
+            Err(err) => {
+                println!("Failed to load layout:\n{}", err);
+                std::process::exit(1);
+            }
+        },
+    };
+
     let (view_request_tx, view_request_rx) = mpsc::channel(32);
 
     let (valid_id_tx, valid_id_rx) = watch::channel(BTreeSet::new());
 
     let (derail_tx, derail_rx) = mpsc::channel(1);
 
-    tokio::spawn(async move { train_master(view_request_rx, valid_id_tx, derail_rx).await });
+    let (rerail_tx, rerail_rx) = mpsc::channel(1);
+
+    let (reload_tx, reload_rx) = mpsc::channel(1);
+
+    let (send_tx, send_rx) = mpsc::channel(1);
+
+    let (snapshot_tx, snapshot_rx) = mpsc::channel(1);
+
+    let (arrivals_tx, arrivals_rx) = mpsc::channel(1);
+
+    let (screens_tx, screens_rx) = mpsc::channel(1);
+
+    let (place_tx, place_rx) = mpsc::channel(1);
+
+    let admin_rx = AdminReceivers {
+        derail_rx,
+        rerail_rx,
+        reload_rx,
+        send_rx,
+        snapshot_rx,
+        arrivals_rx,
+        screens_rx,
+        place_rx,
+    };
+    let master_config = config.clone();
+    tokio::spawn(async move {
+        train_master(
+            view_request_rx,
+            valid_id_tx,
+            admin_rx,
+            layout,
+            master_config,
+        )
+        .await
+    });
+
+    if config.save_interval > 0f64 {
+        tokio::spawn(snapshot_saver(
+            config.state.clone(),
+            Duration::from_secs_f64(config.save_interval),
+            snapshot_tx.clone(),
+        ));
+    }
+
+    if config.watch_interval > 0f64 {
+        tokio::spawn(layout_watcher(
+            config.layout.clone(),
+            Duration::from_secs_f64(config.watch_interval),
+            reload_tx.clone(),
+        ));
+    }
 
     // build our application with a single route
 
@@ -618,25 +1875,149 @@ async fn main() {
         view_request_tx,
         valid_id: valid_id_rx,
         derail_tx,
+        rerail_tx,
+        reload_tx,
+        send_tx,
+        arrivals_tx,
+        screens_tx,
+        place_tx,
+        layout_path: config.layout.clone(),
+        admin_token: config.admin_token.clone(),
     };
 
-    let assets_dir = std::path::PathBuf::from("../frontend/");
-
-    let app: Router = Router::new()
-        .fallback_service(axum::routing::get_service(
-            tower_http::services::ServeDir::new(assets_dir).append_index_html_on_directories(true),
-        ))
+    let admin: Router<AppState> = Router::new()
         .route(
             "/derailer",
             axum::routing::get_service(tower_http::services::ServeFile::new(
-                "../frontend/derailer.html",
+                config.assets_dir.join("derailer.html"),
             )),
         )
-        .route("/ws", get(ws_get_handler))
         .route("/force-derail", get(derail_handler))
-        .with_state(shared_state);
+        .route("/rerail", get(rerail_handler))
+        .route("/reload-layout", get(reload_handler))
+        .route("/send-train", get(send_handler))
+        .route("/screens", get(screens_handler))
+        .route("/place-screen", get(place_handler))
+        .layer(axum::middleware::from_fn_with_state(
+            shared_state.clone(),
+            admin_guard,
+        ));
 
-    let location = option_env!("TRAIN_SITE_LOCATION").unwrap_or("0.0.0.0:8080");
-    let listener = tokio::net::TcpListener::bind(location).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    let mut app: Router<AppState> = Router::new()
+        .fallback_service(axum::routing::get_service(
+            tower_http::services::ServeDir::new(&config.assets_dir)
+                .append_index_html_on_directories(true),
+        ))
+        .route("/ws", get(ws_get_handler))
+        .route("/arrivals", get(arrivals_handler));
+    if !config.disable_admin {
+        app = app.merge(admin);
+    }
+    let app: Router = app.with_state(shared_state);
+
+    let listener = match tokio::net::TcpListener::bind(&config.bind).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            println!("Failed to listen on {}: {}", config.bind, err);
+            std::process::exit(1);
+        }
+    };
+
+    // websockets never finish on their own, so stop serving right away instead of shutting down gracefully
+    tokio::select! {
+        result = axum::serve(listener, app) => result.unwrap(),
+        _ = shutdown_signal() => println!("Shutting down..."),
+    }
+    save_snapshot(&snapshot_tx, &config.state).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(offset: Coord, size: Coord) -> Screen {
+        Screen {
+            name: None,
+            offset,
+            size,
+        }
+    }
+
+    #[test]
+    fn screens_see_what_is_on_them_or_just_off_them() {
+        let screen = screen(Coord(1000f64, 0f64), Coord(800f64, 600f64));
+        // inside, across the left edge, and just off the right edge
+        assert!(screen.sees((Coord(1100f64, 100f64), Coord(1200f64, 200f64))));
+        assert!(screen.sees((Coord(0f64, 100f64), Coord(1100f64, 200f64))));
+        assert!(screen.sees((
+            Coord(1800f64 + VIEW_MARGIN / 2f64, 100f64),
+            Coord(2000f64, 200f64)
+        )));
+        // well off to the left, above and below
+        assert!(!screen.sees((
+            Coord(0f64, 0f64),
+            Coord(1000f64 - VIEW_MARGIN * 2f64, 600f64)
+        )));
+        assert!(!screen.sees((Coord(1100f64, -500f64), Coord(1200f64, -VIEW_MARGIN * 2f64))));
+        assert!(!screen.sees((
+            Coord(1100f64, 600f64 + VIEW_MARGIN * 2f64),
+            Coord(1200f64, 1000f64)
+        )));
+    }
+
+    #[test]
+    fn only_screens_a_train_passes_feel_it() {
+        let screen = screen(Coord(0f64, 0f64), Coord(800f64, 600f64));
+        let middle = Coord(400f64, 300f64);
+        let rumble = |position, speed| screen.vibration(VibrationPattern::Rumble, position, speed);
+
+        // stronger in the middle than near a corner
+        let (strong, _) = rumble(middle, VIBRATION_SPEED).unwrap();
+        let (weak, _) = rumble(Coord(700f64, 500f64), VIBRATION_SPEED).unwrap();
+        assert!(strong > weak);
+
+        // off the screen, or standing still
+        assert_eq!(rumble(Coord(810f64, 300f64), VIBRATION_SPEED), None);
+        assert_eq!(
+            screen.vibration(
+                VibrationPattern::Bump,
+                Coord(400f64, -10f64),
+                VIBRATION_SPEED
+            ),
+            None
+        );
+        assert_eq!(rumble(middle, 0f64), None);
+    }
+
+    #[test]
+    fn crashes_are_felt_off_the_screen_within_range() {
+        let screen = screen(Coord(0f64, 0f64), Coord(800f64, 600f64));
+        let crash =
+            |x| screen.vibration(VibrationPattern::Crash, Coord(x, 300f64), VIBRATION_SPEED);
+        assert!(crash(800f64 + CRASH_RANGE / 2f64).is_some());
+        assert_eq!(crash(800f64 + CRASH_RANGE * 2f64), None);
+    }
+
+    #[test]
+    fn layout_edited_after_the_snapshot_wins() {
+        let dir = std::env::temp_dir().join(format!("train-backend-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (layout, state) = (dir.join("layout.json"), dir.join("state.json"));
+        let touch = |path: &std::path::Path, seconds: u64| {
+            let file = std::fs::File::create(path).unwrap();
+            file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(seconds))
+                .unwrap();
+        };
+
+        // no snapshot to start from yet
+        touch(&layout, 2000);
+        assert!(!edited_after(&layout, &state));
+
+        touch(&state, 1000);
+        assert!(edited_after(&layout, &state));
+        touch(&state, 3000);
+        assert!(!edited_after(&layout, &state));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
@@ -1,12 +1,23 @@
+use serde::{Deserialize, Serialize};
+
 pub type ImageSrc = String;
 pub type TrainID = u32;
 pub type TrackID = u32;
+pub type JunctionID = u32;
+pub type NodeID = u32;
+pub type BlockID = u32;
+pub type StationID = u32;
+pub type Side = bool;
 pub type Color = String;
 pub type Thickness = f64;
 pub type StartT = f64;
+pub type ArcTable = Vec<f64>; // bezier t at evenly spaced fractions of the track length
 pub type Duration = tokio::time::Duration; // ms
+pub type Length = f64; // px
+pub type Speed = f64; // px/s
+pub type Acceleration = f64; // px/s^2, negative when slowing down
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Coord(pub f64, pub f64); // ms
 
 impl std::fmt::Display for Coord {
@@ -15,7 +26,32 @@ impl std::fmt::Display for Coord {
     }
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+impl std::str::FromStr for Coord {
+    type Err = &'static str;
+
+    fn from_str(input: &str) -> Result<Coord, Self::Err> {
+        if input.split(";").count() != 2 {
+            return Err("Coord has unexpected amount of semicolons");
+        }
+
+        let split: Vec<&str> = input.split(";").collect();
+
+        let x = match split[0].parse() {
+            Ok(x) => x,
+            Err(_) => return Err("Coord contains a bad x value"),
+        };
+
+        let y = match split[1].parse() {
+            Ok(y) => y,
+            Err(_) => return Err("Coord contains a bad y value"),
+        };
+
+        Ok(Coord(x, y))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Direction {
     Forward,
     Backward,
@@ -44,13 +80,100 @@ impl std::ops::Not for Direction {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DerailReason {
+    Forced,    // derailed on purpose, by an admin or a click
+    Overspeed, // too fast for a curve
+    Collision, // ran into another train
+}
+
+impl std::fmt::Display for DerailReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                DerailReason::Forced => "forced",
+                DerailReason::Overspeed => "overspeed",
+                DerailReason::Collision => "collision",
+            }
+        )
+    }
+}
+
+// what a vibration feels like, the intensity and duration come along with it
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum VibrationPattern {
+    Rumble, // a train rolling onto the screen
+    Bump,   // a train running over a junction
+    Crash,  // a train flying off its track
+}
+
+impl std::fmt::Display for VibrationPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                VibrationPattern::Rumble => "rumble",
+                VibrationPattern::Bump => "bump",
+                VibrationPattern::Crash => "crash",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum SignalAspect {
+    Red,   // a train is in the block
+    Green, // the block is free to enter
+}
+
+impl std::fmt::Display for SignalAspect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                SignalAspect::Red => "red",
+                SignalAspect::Green => "green",
+            }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<Coord>", into = "Vec<Coord>")]
 pub enum Bezier {
     Bezier2(Coord, Coord),
     Bezier3(Coord, Coord, Coord),
     Bezier4(Coord, Coord, Coord, Coord),
 }
 
+impl TryFrom<Vec<Coord>> for Bezier {
+    type Error = &'static str;
+
+    fn try_from(points: Vec<Coord>) -> Result<Bezier, Self::Error> {
+        match points[..] {
+            [point0, point1] => Ok(Bezier::Bezier2(point0, point1)),
+            [point0, point1, point2] => Ok(Bezier::Bezier3(point0, point1, point2)),
+            [point0, point1, point2, point3] => Ok(Bezier::Bezier4(point0, point1, point2, point3)),
+            _ => Err("Bezier needs 2 to 4 points"),
+        }
+    }
+}
+
+impl From<Bezier> for Vec<Coord> {
+    fn from(bezier: Bezier) -> Vec<Coord> {
+        match bezier {
+            Bezier::Bezier2(point0, point1) => vec![point0, point1],
+            Bezier::Bezier3(point0, point1, point2) => vec![point0, point1, point2],
+            Bezier::Bezier4(point0, point1, point2, point3) => vec![point0, point1, point2, point3],
+        }
+    }
+}
+
 impl std::fmt::Display for Bezier {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -69,33 +192,140 @@ impl std::fmt::Display for Bezier {
 
 #[derive(Debug, Clone)]
 pub enum ServerPacket {
-    PacketTRAIN(TrainID, TrackID, StartT, Duration, Direction, ImageSrc),
-    PacketTRACK(Vec<(TrackID, Bezier, Color, Thickness)>),
+    PacketTRAIN(
+        TrainID,
+        TrackID,
+        StartT,
+        Duration,
+        Direction,
+        Speed,
+        Acceleration,
+        Speed, // speed the train stops accelerating at
+        ImageSrc,
+        Vec<(TrackID, Direction)>, // tracks the cars trail over, latest first and the way they were run over
+        Vec<(Length, ImageSrc, ImageSrc)>, // cars first to last, spacing from the one in front, forward and backward image
+    ),
+    PacketTRACK(Vec<(TrackID, Bezier, Color, Thickness, Length, ArcTable)>),
+    PacketJUNCTION(JunctionID, Side, TrackID, [TrackID; 2]),
+    PacketDERAIL(TrainID, Coord, Coord, Duration, DerailReason, ImageSrc),
+    PacketCOLLISION(TrainID, TrainID, Coord),
+    PacketSIGNAL(BlockID, SignalAspect, Vec<Coord>), // where the block's signals stand
+    PacketPLACEMENT(Coord, f64), // where the screen's top left corner is in the world and how much it's zoomed in
+    PacketVIBRATE(VibrationPattern, f64, Duration), // intensity from 0 to 1
 }
 
 impl std::fmt::Display for ServerPacket {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::PacketTRAIN(train_id, track_id, start_t, duration, direction, image_src) => {
+            Self::PacketTRAIN(
+                train_id,
+                track_id,
+                start_t,
+                duration,
+                direction,
+                speed,
+                acceleration,
+                target_speed,
+                image_src,
+                trail,
+                cars,
+            ) => {
                 write!(
                     f,
-                    "train\n{} {} {} {} {}\n{}",
+                    "train\n{} {} {} {} {} {} {} {}\n{}",
                     train_id,
                     track_id,
                     start_t,
                     duration.as_secs_f64() * 1000f64,
                     direction,
+                    speed,
+                    acceleration,
+                    target_speed,
                     image_src
-                )
+                )?;
+                // single sprite trains leave out the car lines altogether
+                if cars.is_empty() {
+                    return Ok(());
+                }
+                writeln!(f)?;
+                for (i, (track_id, direction)) in trail.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{};{}", track_id, direction)?;
+                }
+                for (spacing, image_forward, image_backward) in cars {
+                    write!(f, "\n{} {} {}", spacing, image_forward, image_backward)?;
+                }
+                Ok(())
             }
 
             Self::PacketTRACK(tracks) => {
                 write!(f, "track\n{}", tracks.len())?;
                 for track in tracks {
-                    write!(f, "\n{} {} {} {}", track.0, track.1, track.2, track.3)?;
+                    write!(
+                        f,
+                        "\n{} {} {} {} {} ",
+                        track.0, track.1, track.2, track.3, track.4
+                    )?;
+                    for (i, t) in track.5.iter().enumerate() {
+                        if i != 0 {
+                            write!(f, ";")?;
+                        }
+                        write!(f, "{:.5}", t)?;
+                    }
+                }
+                Ok(())
+            }
+
+            Self::PacketJUNCTION(junction_id, side, track_id, sides) => {
+                write!(
+                    f,
+                    "junction\n{} {}\n{} {} {}",
+                    junction_id, *side as u8, track_id, sides[0], sides[1]
+                )
+            }
+
+            Self::PacketDERAIL(train_id, position, velocity, elapsed, reason, image_src) => {
+                write!(
+                    f,
+                    "derail\n{} {} {} {} {}\n{}",
+                    train_id,
+                    position,
+                    velocity,
+                    elapsed.as_secs_f64() * 1000f64,
+                    reason,
+                    image_src
+                )
+            }
+
+            Self::PacketCOLLISION(train_a, train_b, position) => {
+                write!(f, "collision\n{} {} {}", train_a, train_b, position)
+            }
+
+            Self::PacketSIGNAL(block_id, aspect, positions) => {
+                write!(f, "signal\n{} {}\n", block_id, aspect)?;
+                for (i, position) in positions.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", position)?;
                 }
                 Ok(())
             }
+            Self::PacketPLACEMENT(offset, scale) => {
+                write!(f, "placement\n{} {}", offset, scale)
+            }
+
+            Self::PacketVIBRATE(pattern, intensity, duration) => {
+                write!(
+                    f,
+                    "vibrate\n{} {} {}",
+                    pattern,
+                    intensity,
+                    duration.as_secs_f64() * 1000f64
+                )
+            }
         }
     }
 }
@@ -113,6 +343,12 @@ pub struct ClickModifier {
     alt: bool,
 }
 
+impl ClickModifier {
+    pub fn new(ctrl: bool, shift: bool, alt: bool) -> Self {
+        ClickModifier { ctrl, shift, alt }
+    }
+}
+
 impl std::str::FromStr for ClickModifier {
     type Err = &'static str;
 
@@ -145,23 +381,27 @@ impl std::str::FromStr for ClickModifier {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ClientPacket {
     PacketCLICK(TrainID, ClickModifier),
+    PacketNEWNODE(JunctionID, TrackID, Coord, Coord),
+    PacketNEWTRAIN(Coord, TrackID),
+    PacketMOVEJUNCTION(JunctionID, Side),
+    PacketVIEWPORT(Coord, Coord, Option<String>), // offset and size of the part of the world shown, screen name
 }
 
 impl std::str::FromStr for ClientPacket {
     type Err = &'static str;
 
     fn from_str(input: &str) -> Result<ClientPacket, Self::Err> {
-        if input.split("\n").count() != 2 {
-            return Err("Packet has unexpected amount of lines");
-        }
-
         let split: Vec<&str> = input.split("\n").collect();
 
         match split[0] {
             "click" => {
+                if split.len() != 2 {
+                    return Err("Packet has unexpected amount of lines");
+                }
+
                 if split[1].split(" ").count() != 2 {
                     return Err("Packet has unexpected amount of whitespaces");
                 }
@@ -178,7 +418,207 @@ impl std::str::FromStr for ClientPacket {
 
                 Ok(ClientPacket::PacketCLICK(id, modifier))
             }
+            "newnode" => {
+                if split.len() != 3 {
+                    return Err("Packet has unexpected amount of lines");
+                }
+
+                if split[1].split(" ").count() != 2 || split[2].split(" ").count() != 2 {
+                    return Err("Packet has unexpected amount of whitespaces");
+                }
+
+                let split_2: Vec<_> = split[1].split(" ").collect();
+                let junction_id = match split_2[0].parse() {
+                    Ok(id) => id,
+                    Err(_) => return Err("Packet contains a bad junction id number"),
+                };
+                let track_id = match split_2[1].parse() {
+                    Ok(id) => id,
+                    Err(_) => return Err("Packet contains a bad track id number"),
+                };
+
+                let split_3: Vec<_> = split[2].split(" ").collect();
+                let start: Coord = split_3[0].parse()?;
+                let end: Coord = split_3[1].parse()?;
+                if !start.0.is_finite() || !start.1.is_finite() {
+                    return Err("Packet contains a bad start coordinate");
+                }
+                if !end.0.is_finite() || !end.1.is_finite() {
+                    return Err("Packet contains a bad end coordinate");
+                }
+
+                Ok(ClientPacket::PacketNEWNODE(
+                    junction_id,
+                    track_id,
+                    start,
+                    end,
+                ))
+            }
+            "newtrain" => {
+                if split.len() != 2 {
+                    return Err("Packet has unexpected amount of lines");
+                }
+
+                if split[1].split(" ").count() != 2 {
+                    return Err("Packet has unexpected amount of whitespaces");
+                }
+
+                let split_2: Vec<_> = split[1].split(" ").collect();
+                let position: Coord = split_2[0].parse()?;
+                if !position.0.is_finite() || !position.1.is_finite() {
+                    return Err("Packet contains a bad train position");
+                }
+                let track_id = match split_2[1].parse() {
+                    Ok(id) => id,
+                    Err(_) => return Err("Packet contains a bad track id number"),
+                };
+
+                Ok(ClientPacket::PacketNEWTRAIN(position, track_id))
+            }
+            "movejunction" => {
+                if split.len() != 2 {
+                    return Err("Packet has unexpected amount of lines");
+                }
+
+                if split[1].split(" ").count() != 2 {
+                    return Err("Packet has unexpected amount of whitespaces");
+                }
+
+                let split_2: Vec<_> = split[1].split(" ").collect();
+                let junction_id = match split_2[0].parse() {
+                    Ok(id) => id,
+                    Err(_) => return Err("Packet contains a bad junction id number"),
+                };
+                let side = match split_2[1] {
+                    "0" => false,
+                    "1" => true,
+                    _ => return Err("Packet contains a bad junction side"),
+                };
+
+                Ok(ClientPacket::PacketMOVEJUNCTION(junction_id, side))
+            }
+            "viewport" => {
+                if split.len() != 2 && split.len() != 3 {
+                    return Err("Packet has unexpected amount of lines");
+                }
+
+                if split[1].split(" ").count() != 2 {
+                    return Err("Packet has unexpected amount of whitespaces");
+                }
+
+                let split_2: Vec<_> = split[1].split(" ").collect();
+                let offset: Coord = split_2[0].parse()?;
+                let size: Coord = split_2[1].parse()?;
+                if !offset.0.is_finite() || !offset.1.is_finite() {
+                    return Err("Packet contains a bad viewport offset");
+                }
+                if !(size.0 >= 0f64 && size.1 >= 0f64 && size.0.is_finite() && size.1.is_finite()) {
+                    return Err("Packet contains a bad viewport size");
+                }
+                let name = split
+                    .get(2)
+                    .map(|name| name.trim())
+                    .filter(|name| !name.is_empty())
+                    .map(String::from);
+
+                Ok(ClientPacket::PacketVIEWPORT(offset, size, name))
+            }
             _ => Err("Packet contained a unexpected type identifier"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<ClientPacket, &'static str> {
+        input.parse()
+    }
+
+    #[test]
+    fn well_formed_packets_parse() {
+        assert_eq!(
+            parse("click\n3 1,0,1"),
+            Ok(ClientPacket::PacketCLICK(
+                3,
+                ClickModifier {
+                    ctrl: true,
+                    shift: false,
+                    alt: true
+                }
+            ))
+        );
+        assert_eq!(
+            parse("newnode\n2 7\n10;20 30.5;-40"),
+            Ok(ClientPacket::PacketNEWNODE(
+                2,
+                7,
+                Coord(10f64, 20f64),
+                Coord(30.5f64, -40f64)
+            ))
+        );
+        assert_eq!(
+            parse("newtrain\n1;2 5"),
+            Ok(ClientPacket::PacketNEWTRAIN(Coord(1f64, 2f64), 5))
+        );
+        assert_eq!(
+            parse("movejunction\n4 1"),
+            Ok(ClientPacket::PacketMOVEJUNCTION(4, true))
+        );
+        assert_eq!(
+            parse("viewport\n0;0 800;600\n left "),
+            Ok(ClientPacket::PacketVIEWPORT(
+                Coord(0f64, 0f64),
+                Coord(800f64, 600f64),
+                Some("left".into())
+            ))
+        );
+        assert_eq!(
+            parse("viewport\n0;0 800;600"),
+            Ok(ClientPacket::PacketVIEWPORT(
+                Coord(0f64, 0f64),
+                Coord(800f64, 600f64),
+                None
+            ))
+        );
+    }
+
+    #[test]
+    fn malformed_packets_are_rejected() {
+        for input in [
+            "",
+            "honk\n1",
+            "click",
+            "click\n3",
+            "click\n3 1,0",
+            "click\n-3 0,0,0",
+            "click\n3 0,0,2",
+            "newnode\n2 7",
+            "newnode\n2 7\n10;20",
+            "newnode\n2 7\n10 20 30 40",
+            "newtrain\n1;2;3 5",
+            "newtrain\n1;2 track",
+            "movejunction\n4 2",
+            "viewport\n0;0 -800;600",
+            "viewport\n0;0 800;600\nleft\nright",
+        ] {
+            assert!(parse(input).is_err(), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn non_finite_coordinates_are_rejected() {
+        for bad in ["NaN", "inf", "-inf", "infinity"] {
+            for input in [
+                format!("newnode\n0 9\n{};0 10;10", bad),
+                format!("newnode\n0 9\n0;0 10;{}", bad),
+                format!("newtrain\n{};0 0", bad),
+                format!("viewport\n0;{} 800;600", bad),
+                format!("viewport\n0;0 {};600", bad),
+            ] {
+                assert!(parse(&input).is_err(), "{:?}", input);
+            }
+        }
+    }
+}
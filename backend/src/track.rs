@@ -0,0 +1,569 @@
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
+
+use crate::layout::{JunctionLayout, StationLayout, TrackLayout};
+use crate::packet::*;
+
+// how far a coordinate may be from a node to be considered on it
+const NODE_SNAP_DISTANCE: f64 = 20f64; // px
+
+// resolution of the table clients use to turn progress into bezier t
+const ARC_TABLE_PIECES: usize = 32;
+
+// the most sideways acceleration a train takes on a curve before flying off it
+const MAX_LATERAL_ACCELERATION: f64 = 6000f64; // px/s^2
+
+pub struct TrackPiece {
+    pub path: Bezier,         // px
+    pub color: Color,         // #FFFFFF
+    pub thickness: Thickness, // px
+    pub length: f64,          // px
+    pub arc_table: ArcTable,  // t at evenly spaced progress
+    pub start: NodeID,        // node at progress 0
+    pub end: NodeID,          // node at progress 1
+    pub block: BlockID,       // trains wait at its signals while another train is in it
+    pub min_radius: f64,      // px, radius of curvature at the tightest point
+    pub tightest_progress: f64,
+    pub stations: Vec<(StationID, f64)>, // stations on the track and their progress
+    pub bounds: (Coord, Coord), // px, top left and bottom right corners of a box around the track
+}
+
+impl TrackPiece {
+    pub fn new(
+        path: Bezier,
+        color: Color,
+        thickness: Thickness,
+        start: NodeID,
+        end: NodeID,
+        block: BlockID,
+    ) -> Self {
+        let tightest_t = path.tightest_t();
+        let mut track = TrackPiece {
+            path,
+            color,
+            thickness,
+            length: path.length(),
+            arc_table: path.arc_length_table(ARC_TABLE_PIECES),
+            start,
+            end,
+            block,
+            min_radius: path.radius(tightest_t),
+            tightest_progress: 0f64,
+            stations: Vec::new(),
+            bounds: path.bounds(),
+        };
+        track.tightest_progress = track.progress_at(tightest_t);
+        track
+    }
+
+    // fastest a train can go over the whole track without derailing
+    pub fn speed_limit(&self) -> f64 {
+        (MAX_LATERAL_ACCELERATION * self.min_radius).sqrt()
+    }
+
+    // bezier t at a given progress, interpolated from the arc length table
+    pub fn t_at(&self, progress: f64) -> f64 {
+        let pieces = self.arc_table.len() - 1;
+        let position = progress.clamp(0f64, 1f64) * pieces as f64;
+        let i = (position as usize).min(pieces - 1);
+        self.arc_table[i] + (self.arc_table[i + 1] - self.arc_table[i]) * (position - i as f64)
+    }
+
+    pub fn position(&self, progress: f64) -> Coord {
+        self.path.point(self.t_at(progress))
+    }
+
+    // progress (fraction of the length) at a given bezier t
+    pub fn progress_at(&self, t: f64) -> f64 {
+        self.path.length_between(0f64, t) / self.length
+    }
+
+    // node reached when running off the track in the given direction
+    fn node_towards(&self, direction: Direction) -> NodeID {
+        match direction {
+            Direction::Forward => self.end,
+            Direction::Backward => self.start,
+        }
+    }
+
+    // heading of a train running off the track in the given direction
+    fn exit_heading(&self, direction: Direction) -> Coord {
+        match direction {
+            Direction::Forward => self.path.derivative(1f64),
+            Direction::Backward => self.path.derivative(0f64) * -1f64,
+        }
+    }
+
+    // heading of a train entering the track in the given direction
+    fn entry_heading(&self, direction: Direction) -> Coord {
+        match direction {
+            Direction::Forward => self.path.derivative(0f64),
+            Direction::Backward => self.path.derivative(1f64) * -1f64,
+        }
+    }
+}
+
+pub struct Junction {
+    pub node: NodeID,        // where the junction is
+    pub track: TrackID, // trains arriving at the node over this track are routed by the junction
+    pub sides: [TrackID; 2], // tracks leaving the node, picked by side
+    pub side: Side,
+}
+
+impl From<TrackLayout> for TrackPiece {
+    fn from(track: TrackLayout) -> Self {
+        TrackPiece::new(
+            track.path,
+            track.color,
+            track.thickness,
+            track.start,
+            track.end,
+            track.block.unwrap_or(track.id),
+        )
+    }
+}
+
+pub struct Station {
+    pub name: String,
+    pub track: TrackID,
+    pub progress: f64,
+    pub dwell: f64, // s
+}
+
+impl From<StationLayout> for Station {
+    fn from(station: StationLayout) -> Self {
+        Station {
+            name: station.name,
+            track: station.track,
+            progress: station.progress,
+            dwell: station.dwell,
+        }
+    }
+}
+
+impl Station {
+    // off a track that isn't kept to the closest point of the new tracks, true when it moved
+    pub fn relocate(
+        &mut self,
+        kept: &BTreeSet<TrackID>,
+        tracks: &BTreeMap<u32, TrackPiece>,
+        new_tracks: &BTreeMap<u32, TrackPiece>,
+    ) -> bool {
+        if kept.contains(&self.track) {
+            return false;
+        }
+        let position = tracks.get(&self.track).unwrap().position(self.progress);
+        (self.track, self.progress) = closest_progress(position, new_tracks).unwrap();
+        true
+    }
+}
+
+// let every track know the stations on it
+pub fn place_stations(
+    tracks: &mut BTreeMap<u32, TrackPiece>,
+    stations: &BTreeMap<StationID, Station>,
+) {
+    for track in tracks.values_mut() {
+        track.stations.clear();
+    }
+    for (id, station) in stations.iter() {
+        if let Some(track) = tracks.get_mut(&station.track) {
+            track.stations.push((*id, station.progress));
+        }
+    }
+}
+
+impl From<JunctionLayout> for Junction {
+    fn from(junction: JunctionLayout) -> Self {
+        Junction {
+            node: junction.node,
+            track: junction.track,
+            sides: junction.sides,
+            side: junction.side,
+        }
+    }
+}
+
+impl Junction {
+    pub fn to_packet(&self, id: JunctionID) -> ServerPacket {
+        ServerPacket::PacketJUNCTION(id, self.side, self.track, self.sides)
+    }
+}
+
+// where a train goes after running off a track: the side picked by a junction routing trains
+// from this track, otherwise the other track at the node that turns the least,
+// or back along the same track when the node is a dead end
+pub fn next_track(
+    track_id: TrackID,
+    direction: Direction,
+    tracks: &BTreeMap<u32, TrackPiece>,
+    junctions: &BTreeMap<JunctionID, Junction>,
+) -> (TrackID, Direction) {
+    let track = tracks.get(&track_id).unwrap();
+    let node = track.node_towards(direction);
+    let heading = track.exit_heading(direction);
+
+    // every way out of the node except going back the way the train came
+    let exits: Vec<(TrackID, Direction)> = tracks
+        .iter()
+        .flat_map(|(id, track)| {
+            [
+                (track.start == node).then_some((*id, Direction::Forward)),
+                (track.end == node).then_some((*id, Direction::Backward)),
+            ]
+        })
+        .flatten()
+        .filter(|exit| *exit != (track_id, !direction))
+        .collect();
+
+    let junction = junctions
+        .values()
+        .find(|junction| junction.node == node && junction.track == track_id);
+    if let Some(junction) = junction {
+        let side = junction.sides[junction.side as usize];
+        if let Some(exit) = exits.iter().find(|(id, _)| *id == side) {
+            return *exit;
+        }
+    }
+
+    let alignment = |(id, direction): &(TrackID, Direction)| {
+        let entry = tracks.get(id).unwrap().entry_heading(*direction);
+        heading.dot(entry) / heading.length() / entry.length()
+    };
+    exits
+        .into_iter()
+        .max_by(|a, b| alignment(a).total_cmp(&alignment(b)))
+        .unwrap_or((track_id, !direction))
+}
+
+pub fn node_position(node: NodeID, tracks: &BTreeMap<u32, TrackPiece>) -> Option<Coord> {
+    tracks.values().find_map(|track| {
+        if track.start == node {
+            Some(track.path.point(0f64))
+        } else if track.end == node {
+            Some(track.path.point(1f64))
+        } else {
+            None
+        }
+    })
+}
+
+// the track and progress closest to position
+pub fn closest_progress(
+    position: Coord,
+    tracks: &BTreeMap<u32, TrackPiece>,
+) -> Option<(TrackID, f64)> {
+    tracks
+        .iter()
+        .map(|(id, track)| {
+            let t = track.path.closest_t(position);
+            (*id, track, t, track.path.point(t).distance(position))
+        })
+        .min_by(|a, b| a.3.total_cmp(&b.3))
+        .map(|(id, track, t, _)| (id, track.progress_at(t)))
+}
+
+// the tracks laid again the same, along the same path between the same nodes, so whatever is on them
+// stays where it is; a new track may take the id of an old one laid elsewhere
+pub fn kept_tracks(
+    tracks: &BTreeMap<u32, TrackPiece>,
+    new_tracks: &BTreeMap<u32, TrackPiece>,
+) -> BTreeSet<TrackID> {
+    new_tracks
+        .iter()
+        .filter(|(id, new)| {
+            tracks
+                .get(id)
+                .is_some_and(|old| (old.path, old.start, old.end) == (new.path, new.start, new.end))
+        })
+        .map(|(id, _)| *id)
+        .collect()
+}
+
+// the node closest to position, if any is within NODE_SNAP_DISTANCE
+pub fn node_at(position: Coord, tracks: &BTreeMap<u32, TrackPiece>) -> Option<NodeID> {
+    tracks
+        .values()
+        .flat_map(|track| {
+            [
+                (track.start, track.path.point(0f64)),
+                (track.end, track.path.point(1f64)),
+            ]
+        })
+        .map(|(node, point)| (node, point.distance(position)))
+        .filter(|(_, distance)| *distance <= NODE_SNAP_DISTANCE)
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(node, _)| node)
+}
+
+// the junction deciding where a train goes after running off the track in the given direction
+pub fn junction_ahead(
+    track_id: TrackID,
+    direction: Direction,
+    tracks: &BTreeMap<u32, TrackPiece>,
+    junctions: &BTreeMap<JunctionID, Junction>,
+) -> Option<JunctionID> {
+    let node = tracks.get(&track_id).unwrap().node_towards(direction);
+    junctions
+        .iter()
+        .find(|(_, junction)| junction.node == node && junction.track == track_id)
+        .map(|(id, _)| *id)
+}
+
+// the first junction a train runs into from the track it's on, following the way junctions are set now
+pub fn next_junction(
+    mut track_id: TrackID,
+    mut direction: Direction,
+    tracks: &BTreeMap<u32, TrackPiece>,
+    junctions: &BTreeMap<JunctionID, Junction>,
+) -> Option<JunctionID> {
+    // going over every track both ways without meeting one means there's none
+    for _ in 0..tracks.len() * 2 {
+        if let Some(junction_id) = junction_ahead(track_id, direction, tracks, junctions) {
+            return Some(junction_id);
+        }
+        (track_id, direction) = next_track(track_id, direction, tracks, junctions);
+    }
+    None
+}
+
+// the junction sides to set along the shortest way for a train at progress on a track, heading in direction,
+// to get to progress on another track; none when the way there doesn't go over any junction,
+// nothing when there's no way there
+pub fn plan_route(
+    from: (TrackID, Direction, f64),
+    to: (TrackID, f64),
+    tracks: &BTreeMap<u32, TrackPiece>,
+    junctions: &BTreeMap<JunctionID, Junction>,
+) -> Option<Vec<(JunctionID, Side)>> {
+    let (track_id, direction, progress) = from;
+    let ahead = match direction {
+        Direction::Forward => to.1 > progress,
+        Direction::Backward => to.1 < progress,
+    };
+    if track_id == to.0 && ahead {
+        return Some(Vec::new());
+    }
+
+    // where running off a track leads, a junction leads to either side
+    let exits = |track_id: TrackID, direction: Direction| match junction_ahead(
+        track_id, direction, tracks, junctions,
+    ) {
+        Some(junction_id) => {
+            let junction = &junctions[&junction_id];
+            [false, true]
+                .into_iter()
+                .map(|side| {
+                    let side_track = junction.sides[side as usize];
+                    let direction = match tracks.get(&side_track).unwrap().start == junction.node {
+                        true => Direction::Forward,
+                        false => Direction::Backward,
+                    };
+                    ((side_track, direction), Some((junction_id, side)))
+                })
+                .collect()
+        }
+        None => vec![(next_track(track_id, direction, tracks, junctions), None)],
+    };
+
+    // the way onto every track, shortest first; the train's own track counts again once it's been around
+    let to_end = match direction {
+        Direction::Forward => 1f64 - progress,
+        Direction::Backward => progress,
+    } * tracks.get(&track_id).unwrap().length;
+    let mut queue = BinaryHeap::new();
+    for (exit, via) in exits(track_id, direction) {
+        queue.push((
+            std::cmp::Reverse(ordered_float::OrderedFloat(to_end)),
+            exit,
+            None,
+            via,
+        ));
+    }
+    // how every track was got onto: from which track and over which junction side
+    let mut reached = BTreeMap::new();
+    while let Some((std::cmp::Reverse(distance), (track_id, direction), previous, via)) =
+        queue.pop()
+    {
+        if reached.contains_key(&(track_id, direction)) {
+            continue;
+        }
+        reached.insert((track_id, direction), (previous, via));
+
+        if track_id == to.0 {
+            let mut route = Vec::new();
+            let mut current = Some((track_id, direction));
+            while let Some(state) = current {
+                let (previous, via) = reached[&state];
+                route.extend(via);
+                current = previous;
+            }
+            route.reverse();
+            return Some(route);
+        }
+
+        let length = tracks.get(&track_id).unwrap().length;
+        for (exit, via) in exits(track_id, direction) {
+            queue.push((
+                std::cmp::Reverse(distance + length),
+                exit,
+                Some((track_id, direction)),
+                via,
+            ));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a straight track running into a junction that leads on straight ahead or off to the side,
+    // and a track on its own nowhere near them
+    fn layout() -> (BTreeMap<u32, TrackPiece>, BTreeMap<JunctionID, Junction>) {
+        let track = |id, path, start, end| {
+            let track = TrackLayout {
+                id,
+                path,
+                color: "#FFFFFF".into(),
+                thickness: 10f64,
+                start,
+                end,
+                block: None,
+            };
+            (id, TrackPiece::from(track))
+        };
+        let tracks = BTreeMap::from([
+            track(
+                0,
+                Bezier::Bezier2(Coord(0f64, 0f64), Coord(100f64, 0f64)),
+                0,
+                1,
+            ),
+            track(
+                1,
+                Bezier::Bezier2(Coord(100f64, 0f64), Coord(200f64, 0f64)),
+                1,
+                2,
+            ),
+            track(
+                2,
+                Bezier::Bezier3(
+                    Coord(100f64, 0f64),
+                    Coord(150f64, 0f64),
+                    Coord(200f64, 100f64),
+                ),
+                1,
+                3,
+            ),
+            track(
+                3,
+                Bezier::Bezier2(Coord(0f64, 500f64), Coord(100f64, 500f64)),
+                4,
+                5,
+            ),
+        ]);
+        let junctions = BTreeMap::from([(
+            0,
+            Junction {
+                node: 1,
+                track: 0,
+                sides: [1, 2],
+                side: false,
+            },
+        )]);
+        (tracks, junctions)
+    }
+
+    #[test]
+    fn route_sets_the_junction_to_the_side_leading_there() {
+        let (tracks, junctions) = layout();
+        let from = (0, Direction::Forward, 0.5f64);
+        assert_eq!(
+            plan_route(from, (1, 0.5f64), &tracks, &junctions),
+            Some(vec![(0, false)])
+        );
+        assert_eq!(
+            plan_route(from, (2, 0.5f64), &tracks, &junctions),
+            Some(vec![(0, true)])
+        );
+    }
+
+    #[test]
+    fn route_ahead_on_the_same_track_sets_nothing() {
+        let (tracks, junctions) = layout();
+        assert_eq!(
+            plan_route(
+                (0, Direction::Forward, 0.2f64),
+                (0, 0.8f64),
+                &tracks,
+                &junctions
+            ),
+            Some(Vec::new())
+        );
+    }
+
+    #[test]
+    fn no_route_to_a_track_that_isnt_connected() {
+        let (tracks, junctions) = layout();
+        assert_eq!(
+            plan_route(
+                (0, Direction::Forward, 0.5f64),
+                (3, 0.5f64),
+                &tracks,
+                &junctions
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn dead_end_sends_the_train_back() {
+        let (tracks, junctions) = layout();
+        assert_eq!(
+            next_track(1, Direction::Forward, &tracks, &junctions),
+            (1, Direction::Backward)
+        );
+    }
+
+    #[test]
+    fn only_tracks_laid_the_same_are_kept() {
+        let (tracks, _) = layout();
+        let (mut new_tracks, _) = layout();
+        // repainted is still the same track, laid to another node or along another path isn't
+        new_tracks.get_mut(&0).unwrap().color = "#000000".into();
+        new_tracks.get_mut(&1).unwrap().end = 6;
+        new_tracks.get_mut(&2).unwrap().path =
+            Bezier::Bezier2(Coord(100f64, 0f64), Coord(200f64, 100f64));
+        new_tracks.remove(&3);
+        assert_eq!(kept_tracks(&tracks, &new_tracks), BTreeSet::from([0]));
+    }
+
+    #[test]
+    fn stations_on_tracks_not_kept_move_to_the_closest_new_one() {
+        let (tracks, _) = layout();
+        let (mut new_tracks, _) = layout();
+        new_tracks.remove(&2);
+        let kept = kept_tracks(&tracks, &new_tracks);
+        let station = |track, progress| Station {
+            name: "station".into(),
+            track,
+            progress,
+            dwell: 0f64,
+        };
+
+        let mut stays = station(1, 0.5f64);
+        assert!(!stays.relocate(&kept, &tracks, &new_tracks));
+        assert_eq!((stays.track, stays.progress), (1, 0.5f64));
+
+        // from the far end of the curve to the end of the straight track above it
+        let mut moves = station(2, 1f64);
+        assert!(moves.relocate(&kept, &tracks, &new_tracks));
+        assert_eq!(moves.track, 1);
+        assert!(
+            (moves.progress - 1f64).abs() < 0.01f64,
+            "{}",
+            moves.progress
+        );
+    }
+}
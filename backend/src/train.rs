@@ -0,0 +1,1080 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::layout::TrainLayout;
+use crate::packet::*;
+use crate::track::{closest_progress, junction_ahead, next_track, Junction, Station, TrackPiece};
+
+// how fast trains take junctions, they brake ahead of one to get there at this speed
+const JUNCTION_SPEED: f64 = 150f64; // px/s
+
+// how far trains may close in on each other between two checks when predicting collisions
+const COLLISION_STEP: f64 = 5f64; // px
+
+// how far ahead to look when telling whether touching trains are closing in on each other
+pub const COLLISION_PROBE: f64 = 0.001f64; // s
+
+// how far short of a station a train braking for it may come to a stop and still be at it
+const STATION_TOLERANCE: f64 = 1f64; // px
+
+#[derive(Clone)]
+pub struct TrainProperties {
+    pub speed: f64,        // px/s, cruising speed
+    pub max_speed: f64,    // px/s
+    pub acceleration: f64, // px/s^2
+    pub deceleration: f64, // px/s^2
+    pub length: f64,       // px, of every car
+    pub cars: Vec<Car>,    // first to last, the leading one is what the train is drawn as
+    pub timetable: Option<Timetable>,
+}
+
+#[derive(Clone)]
+pub struct Timetable {
+    pub period: f64,                  // s, counted from the unix epoch
+    pub stops: Vec<(StationID, f64)>, // station and departure in s into the period, in turn
+}
+
+#[derive(Clone)]
+pub struct Car {
+    pub image_forward: String,
+    pub image_backward: String,
+    pub spacing: f64, // px, from the car in front, nothing for the leading one
+}
+
+#[derive(Clone)]
+pub struct TrainInstance {
+    pub properties: TrainProperties,
+    pub speed: f64,           // px/s, as of updated
+    pub target_speed: f64,    // px/s, cruising speed plus the boost from clicks
+    pub braking: Option<f64>, // px/s, slowing down to reach the end of the current track at this speed
+    pub held: bool,           // waiting at the signal at the end of the current track
+    pub current_track: u32,
+    pub progress: f64, // 0 ~ 1, fraction of the track length, as of updated
+    pub updated: tokio::time::Instant, // when progress and speed were last brought up to date
+    pub direction: Direction, // backward direction: progress goes from 1 to 0
+    pub trail: Vec<(TrackID, Direction)>, // tracks behind the current one the cars are on, latest first
+    pub derailed: Option<Derailment>,
+    pub epoch: u64, // bumped whenever the motion changes, so events planned before are dropped
+    pub next_stop: usize, // timetable stop the train is heading for
+    pub dwelling: Option<(StationID, tokio::time::Instant)>, // stopped at a station until the departure
+    pub destination: Option<StationID>, // sent there, setting junctions on the way
+}
+
+// where a train stops following its current track
+#[derive(Clone, Copy, PartialEq)]
+pub enum Target {
+    TrackEnd,
+    Curve, // the tightest point of the track, going too fast to take it
+    Station(StationID),
+}
+
+// seconds since the unix epoch at an instant, for timetables to stay put across restarts
+fn wall_clock(time: tokio::time::Instant) -> f64 {
+    let now = tokio::time::Instant::now();
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+        + time.saturating_duration_since(now).as_secs_f64()
+        - now.saturating_duration_since(time).as_secs_f64()
+}
+
+#[derive(Clone)]
+pub struct Derailment {
+    pub position: Coord, // px, where the train left its track
+    pub velocity: Coord, // px/s, how fast it was going when it did
+    pub time: tokio::time::Instant,
+    pub reason: DerailReason,
+}
+
+pub fn train_properties(train: &TrainLayout) -> TrainProperties {
+    TrainProperties {
+        speed: train.speed,
+        max_speed: train.max_speed,
+        acceleration: train.acceleration,
+        deceleration: train.deceleration,
+        length: train.length,
+        cars: std::iter::once(Car {
+            image_forward: train.image_forward.clone(),
+            image_backward: train.image_backward.clone(),
+            spacing: 0f64,
+        })
+        .chain(train.cars.iter().map(|car| Car {
+            image_forward: car.image_forward.clone(),
+            image_backward: car.image_backward.clone(),
+            spacing: car.spacing,
+        }))
+        .collect(),
+        timetable: train.timetable.as_ref().map(|timetable| Timetable {
+            period: timetable.period,
+            stops: timetable
+                .stops
+                .iter()
+                .map(|stop| (stop.station, stop.departure))
+                .collect(),
+        }),
+    }
+}
+
+impl TrainInstance {
+    pub fn from_layout(train: TrainLayout, tracks: &BTreeMap<u32, TrackPiece>) -> Self {
+        let mut instance = TrainInstance {
+            properties: train_properties(&train),
+            speed: train.speed,
+            target_speed: train.speed,
+            braking: None,
+            held: false,
+            current_track: train.track,
+            progress: train.progress,
+            updated: tokio::time::Instant::now(),
+            direction: train.direction,
+            trail: Vec::new(),
+            derailed: None,
+            epoch: 0,
+            next_stop: train
+                .timetable
+                .as_ref()
+                .map_or(0, |timetable| timetable.next),
+            dwelling: None,
+            destination: train.destination,
+        };
+        // a saved derailment comes back with the train already at rest
+        if let Some(reason) = train.derailed {
+            instance.derail(reason, tracks);
+            instance.derailed.as_mut().unwrap().velocity = Coord(0f64, 0f64);
+        }
+        instance
+    }
+
+    // the speed the train is speeding up or slowing down to right now
+    fn aimed_speed(&self) -> f64 {
+        if self.dwelling.is_some() {
+            return 0f64;
+        }
+        match self.braking {
+            Some(limit) => self.target_speed.min(limit),
+            None => self.target_speed,
+        }
+    }
+
+    // px/s^2 when going at speed, zero once the aimed speed is reached
+    fn acceleration(&self, speed: f64) -> f64 {
+        let aimed = self.aimed_speed();
+        if speed < aimed {
+            self.properties.acceleration
+        } else if speed > aimed {
+            -self.properties.deceleration
+        } else {
+            0f64
+        }
+    }
+
+    // seconds and px it takes from updated to reach the aimed speed
+    fn settling(&self) -> (f64, f64) {
+        let acceleration = self.acceleration(self.speed);
+        if acceleration == 0f64 {
+            return (0f64, 0f64);
+        }
+        let seconds = (self.aimed_speed() - self.speed) / acceleration;
+        (
+            seconds,
+            self.speed * seconds + acceleration * seconds * seconds / 2f64,
+        )
+    }
+
+    fn speed_after(&self, seconds: f64) -> f64 {
+        let (settle_time, _) = self.settling();
+        self.speed + self.acceleration(self.speed) * seconds.min(settle_time)
+    }
+
+    // px covered in the given seconds after updated
+    fn distance_after(&self, seconds: f64) -> f64 {
+        let (settle_time, settle_distance) = self.settling();
+        if seconds >= settle_time {
+            return settle_distance + self.aimed_speed() * (seconds - settle_time);
+        }
+        self.speed * seconds + self.acceleration(self.speed) * seconds * seconds / 2f64
+    }
+
+    // speed after covering distance px from where the train was at updated
+    fn speed_over(&self, distance: f64) -> f64 {
+        let (_, settle_distance) = self.settling();
+        if distance >= settle_distance {
+            return self.aimed_speed();
+        }
+        (self.speed * self.speed + 2f64 * self.acceleration(self.speed) * distance)
+            .max(0f64)
+            .sqrt()
+    }
+
+    // seconds after updated until distance px are covered, never if the train stops before that
+    fn time_to_cover(&self, distance: f64) -> Option<f64> {
+        if distance <= 0f64 {
+            return Some(0f64);
+        }
+        let (settle_time, settle_distance) = self.settling();
+        if distance <= settle_distance {
+            return Some((self.speed_over(distance) - self.speed) / self.acceleration(self.speed));
+        }
+        match self.aimed_speed() > 0f64 {
+            true => Some(settle_time + (distance - settle_distance) / self.aimed_speed()),
+            false => None,
+        }
+    }
+
+    fn is_moving(&self) -> bool {
+        self.derailed.is_none() && (self.speed > 0f64 || self.aimed_speed() > 0f64)
+    }
+
+    // px between the train as of updated and a progress ahead of it on the current track
+    fn distance_to(&self, progress: f64, tracks: &BTreeMap<u32, TrackPiece>) -> f64 {
+        (progress - self.progress).abs() * tracks.get(&self.current_track).unwrap().length
+    }
+
+    // whether the train stops at the station or passes it by
+    fn stops_at(&self, station_id: StationID) -> bool {
+        if self.destination == Some(station_id) {
+            return true;
+        }
+        match &self.properties.timetable {
+            Some(timetable) => timetable.stops[self.next_stop].0 == station_id,
+            None => true,
+        }
+    }
+
+    // where the train stops following its current track: the end of it, the first station it stops at,
+    // or the tightest point before either when it'll be going too fast there to take it
+    pub fn target_progress(&self, tracks: &BTreeMap<u32, TrackPiece>) -> (f64, Target) {
+        let track = tracks.get(&self.current_track).unwrap();
+        let not_past = |progress: f64, limit: f64| match self.direction {
+            Direction::Forward => progress <= limit,
+            Direction::Backward => progress >= limit,
+        };
+
+        let mut target = match self.direction {
+            Direction::Forward => (1f64, Target::TrackEnd),
+            Direction::Backward => (0f64, Target::TrackEnd),
+        };
+        // a train that has just left a station is still right at it
+        for (station_id, progress) in track.stations.iter() {
+            if *progress != self.progress
+                && not_past(self.progress, *progress)
+                && not_past(*progress, target.0)
+                && self.stops_at(*station_id)
+            {
+                target = (*progress, Target::Station(*station_id));
+            }
+        }
+
+        if not_past(self.progress, track.tightest_progress)
+            && not_past(track.tightest_progress, target.0)
+            && self.speed_over(self.distance_to(track.tightest_progress, tracks))
+                > track.speed_limit()
+        {
+            return (track.tightest_progress, Target::Curve);
+        }
+        target
+    }
+
+    // seconds after updated until the target progress, never for trains that don't get there
+    fn seconds_to_target(&self, tracks: &BTreeMap<u32, TrackPiece>) -> Option<f64> {
+        if !self.is_moving() {
+            return None;
+        }
+        let (target, kind) = self.target_progress(tracks);
+        let distance = self.distance_to(target, tracks);
+        match self.time_to_cover(distance) {
+            // braking for a station stops the train a hair early
+            None if matches!(kind, Target::Station(_))
+                && distance - self.settling().1 < STATION_TOLERANCE =>
+            {
+                Some(self.settling().0)
+            }
+            seconds => seconds,
+        }
+    }
+
+    pub fn arrival_time(&self, tracks: &BTreeMap<u32, TrackPiece>) -> Option<tokio::time::Instant> {
+        self.seconds_to_target(tracks)
+            .map(|seconds| self.updated + Duration::from_secs_f64(seconds))
+    }
+
+    // seconds after updated the train has been following its track by time,
+    // stopping at the target progress since the arrival event takes it from there
+    fn elapsed_at(&self, time: tokio::time::Instant, tracks: &BTreeMap<u32, TrackPiece>) -> f64 {
+        let elapsed = time.saturating_duration_since(self.updated).as_secs_f64();
+        match self.seconds_to_target(tracks) {
+            Some(seconds) => elapsed.min(seconds),
+            None => elapsed,
+        }
+    }
+
+    pub fn progress_at(
+        &self,
+        time: tokio::time::Instant,
+        tracks: &BTreeMap<u32, TrackPiece>,
+    ) -> f64 {
+        if !self.is_moving() {
+            return self.progress;
+        }
+        let travelled = self.distance_after(self.elapsed_at(time, tracks))
+            / tracks.get(&self.current_track).unwrap().length;
+        match self.direction {
+            Direction::Forward => (self.progress + travelled).min(1f64),
+            Direction::Backward => (self.progress - travelled).max(0f64),
+        }
+    }
+
+    pub fn speed_at(&self, time: tokio::time::Instant, tracks: &BTreeMap<u32, TrackPiece>) -> f64 {
+        if !self.is_moving() {
+            return self.speed;
+        }
+        self.speed_after(self.elapsed_at(time, tracks))
+    }
+
+    // px from the leading car to the last one
+    fn consist_length(&self) -> f64 {
+        self.properties.cars.iter().map(|car| car.spacing).sum()
+    }
+
+    // px of the current track behind the train when it's at progress
+    fn behind_on_track(&self, progress: f64, tracks: &BTreeMap<u32, TrackPiece>) -> f64 {
+        tracks.get(&self.current_track).unwrap().length
+            * match self.direction {
+                Direction::Forward => progress,
+                Direction::Backward => 1f64 - progress,
+            }
+    }
+
+    // the place distance px behind the train at progress along its trail: how many trail tracks back,
+    // the track and the progress on it, stopping at the end of the trail
+    fn walk_back(
+        &self,
+        progress: f64,
+        distance: f64,
+        tracks: &BTreeMap<u32, TrackPiece>,
+    ) -> (usize, TrackID, f64) {
+        let mut rest = distance - self.behind_on_track(progress, tracks);
+        if rest <= 0f64 || self.trail.is_empty() {
+            let fraction = distance / tracks.get(&self.current_track).unwrap().length;
+            return match self.direction {
+                Direction::Forward => (0, self.current_track, (progress - fraction).max(0f64)),
+                Direction::Backward => (0, self.current_track, (progress + fraction).min(1f64)),
+            };
+        }
+
+        let length = |i: usize| tracks.get(&self.trail[i].0).unwrap().length;
+        let mut i = 0;
+        while i + 1 < self.trail.len() && rest > length(i) {
+            rest -= length(i);
+            i += 1;
+        }
+        let fraction = rest.min(length(i)) / length(i);
+        let (track_id, direction) = self.trail[i];
+        match direction {
+            Direction::Forward => (i + 1, track_id, 1f64 - fraction),
+            Direction::Backward => (i + 1, track_id, fraction),
+        }
+    }
+
+    // where every car is at time, leading one first
+    pub fn car_positions_at(
+        &self,
+        time: tokio::time::Instant,
+        tracks: &BTreeMap<u32, TrackPiece>,
+    ) -> Vec<Coord> {
+        let progress = self.progress_at(time, tracks);
+        let mut offset = 0f64;
+        self.properties
+            .cars
+            .iter()
+            .map(|car| {
+                offset += car.spacing;
+                let (_, track_id, progress) = self.walk_back(progress, offset, tracks);
+                tracks.get(&track_id).unwrap().position(progress)
+            })
+            .collect()
+    }
+
+    // keep just as much of the trail as the last car needs, making up what's unknown or removed
+    // from the tracks lining up behind the train
+    pub fn fill_trail(&mut self, tracks: &BTreeMap<u32, TrackPiece>) {
+        if let Some(missing) = self
+            .trail
+            .iter()
+            .position(|(id, _)| !tracks.contains_key(id))
+        {
+            self.trail.truncate(missing);
+        }
+
+        let mut needed = self.consist_length() - self.behind_on_track(self.progress, tracks);
+        let mut kept = 0;
+        while needed > 0f64 {
+            if kept == self.trail.len() {
+                // junctions only route trains running into them, going back takes the straightest way
+                let (track_id, direction) = self
+                    .trail
+                    .last()
+                    .copied()
+                    .unwrap_or((self.current_track, self.direction));
+                let (behind, direction) =
+                    next_track(track_id, !direction, tracks, &BTreeMap::new());
+                self.trail.push((behind, !direction));
+            }
+            needed -= tracks.get(&self.trail[kept].0).unwrap().length;
+            kept += 1;
+        }
+        self.trail.truncate(kept);
+    }
+
+    // run the other way with the last car leading, from wherever it is now
+    pub fn turn_around(&mut self, tracks: &BTreeMap<u32, TrackPiece>) {
+        let (index, track_id, progress) =
+            self.walk_back(self.progress, self.consist_length(), tracks);
+        let direction = match index {
+            0 => self.direction,
+            _ => self.trail[index - 1].1,
+        };
+
+        // the tracks between the last car and the leading one are now the ones behind
+        let between: Vec<(TrackID, Direction)> =
+            std::iter::once((self.current_track, self.direction))
+                .chain(self.trail.iter().copied())
+                .take(index)
+                .collect();
+        self.trail = between
+            .into_iter()
+            .rev()
+            .map(|(id, direction)| (id, !direction))
+            .collect();
+        (self.current_track, self.progress, self.direction) = (track_id, progress, !direction);
+        self.braking = None;
+
+        // spacing is to the car in front, so it moves one car along when the order flips
+        let spacings: Vec<f64> = self.properties.cars.iter().map(|car| car.spacing).collect();
+        self.properties.cars.reverse();
+        for (car, spacing) in self
+            .properties
+            .cars
+            .iter_mut()
+            .zip(std::iter::once(0f64).chain(spacings.into_iter().rev()))
+        {
+            car.spacing = spacing;
+        }
+    }
+
+    // bring progress and speed up to date, needed before anything about the motion changes
+    pub fn catch_up(&mut self, time: tokio::time::Instant, tracks: &BTreeMap<u32, TrackPiece>) {
+        (self.progress, self.speed) = (self.progress_at(time, tracks), self.speed_at(time, tracks));
+        self.updated = time;
+    }
+
+    // how fast the train may reach its target: not at all at a station it stops at or when held
+    // at the signal at the end of its track, JUNCTION_SPEED when a junction routes it
+    pub fn end_speed(
+        &self,
+        tracks: &BTreeMap<u32, TrackPiece>,
+        junctions: &BTreeMap<JunctionID, Junction>,
+    ) -> Option<f64> {
+        if self.held || matches!(self.target_progress(tracks).1, Target::Station(_)) {
+            return Some(0f64);
+        }
+        junction_ahead(self.current_track, self.direction, tracks, junctions)
+            .map(|_| JUNCTION_SPEED)
+    }
+
+    // when to start braking so the train gets to the end of the track at its end speed, if it has to
+    pub fn braking_time(
+        &self,
+        tracks: &BTreeMap<u32, TrackPiece>,
+        junctions: &BTreeMap<JunctionID, Junction>,
+    ) -> Option<tokio::time::Instant> {
+        let end_speed = self.end_speed(tracks, junctions)?;
+        if self.braking.is_some()
+            || !self.is_moving()
+            || self.target_progress(tracks).1 == Target::Curve
+        {
+            return None;
+        }
+
+        // how late braking may start, negative once it's too late to reach the end slow enough;
+        // braking a little early keeps a train held at a signal just short of it
+        let remaining = self.distance_to(self.target_progress(tracks).0, tracks);
+        let margin = |distance: f64| {
+            2f64 * self.properties.deceleration * (remaining - distance)
+                - (self.speed_over(distance).powi(2) - end_speed.powi(2))
+        };
+        if margin(remaining) >= 0f64 {
+            return None;
+        }
+        let distance = match margin(0f64) <= 0f64 {
+            true => 0f64,
+            false => {
+                let (mut low, mut high) = (0f64, remaining);
+                for _ in 0..32 {
+                    let middle = (low + high) / 2f64;
+                    match margin(middle) > 0f64 {
+                        true => low = middle,
+                        false => high = middle,
+                    }
+                }
+                low
+            }
+        };
+        self.time_to_cover(distance)
+            .map(|seconds| self.updated + Duration::from_secs_f64(seconds))
+    }
+
+    // when a train arriving at a station at arrival leaves it again: once it has stayed there
+    // for the dwell time, and not before the departure if it's the timetable's next stop
+    fn departure_from(
+        &self,
+        station_id: StationID,
+        station: &Station,
+        arrival: tokio::time::Instant,
+    ) -> tokio::time::Instant {
+        let earliest = arrival + Duration::from_secs_f64(station.dwell);
+        let timetable = match &self.properties.timetable {
+            Some(timetable) if timetable.stops[self.next_stop].0 == station_id => timetable,
+            _ => return earliest,
+        };
+        // the closest departure in the repeating timetable, a late train leaves as soon as it can
+        let (_, departure) = timetable.stops[self.next_stop];
+        let wait = (departure - wall_clock(arrival)).rem_euclid(timetable.period);
+        match wait < timetable.period / 2f64 {
+            true => earliest.max(arrival + Duration::from_secs_f64(wait)),
+            false => earliest,
+        }
+    }
+
+    // the train has just reached its target progress: fly off the curve it's too fast for,
+    // stay at the station, or go on to the next track
+    pub fn arrive(
+        &mut self,
+        tracks: &BTreeMap<u32, TrackPiece>,
+        junctions: &BTreeMap<JunctionID, Junction>,
+        stations: &BTreeMap<StationID, Station>,
+    ) {
+        let (target, kind) = self.target_progress(tracks);
+        self.progress = target;
+        match kind {
+            Target::Curve => {
+                self.derail(DerailReason::Overspeed, tracks);
+                return;
+            }
+            Target::Station(station_id) => {
+                let departure =
+                    self.departure_from(station_id, &stations[&station_id], self.updated);
+                (self.speed, self.braking, self.dwelling) =
+                    (0f64, None, Some((station_id, departure)));
+                return;
+            }
+            Target::TrackEnd => {}
+        }
+
+        let (track_id, direction) =
+            next_track(self.current_track, self.direction, tracks, junctions);
+        // a dead end sends the train back the way it came
+        if (track_id, direction) == (self.current_track, !self.direction) {
+            self.turn_around(tracks);
+            return;
+        }
+        self.trail.insert(0, (self.current_track, self.direction));
+        (self.current_track, self.direction) = (track_id, direction);
+        self.progress = match self.direction {
+            Direction::Forward => 0f64,
+            Direction::Backward => 1f64,
+        };
+        self.braking = None;
+    }
+
+    // leave the station, heading for the next stop of the timetable unless it was just the destination
+    pub fn depart(&mut self) {
+        let station_id = match self.dwelling.take() {
+            Some((station_id, _)) => station_id,
+            None => return,
+        };
+        if self.destination == Some(station_id) {
+            self.destination = None;
+            return;
+        }
+        if let Some(timetable) = &self.properties.timetable {
+            self.next_stop = (self.next_stop + 1) % timetable.stops.len();
+        }
+    }
+
+    // px/s, along the track the way the train is going
+    fn velocity(&self, tracks: &BTreeMap<u32, TrackPiece>) -> Coord {
+        let track = tracks.get(&self.current_track).unwrap();
+        let heading = track.path.derivative(track.t_at(self.progress));
+        heading
+            * (self.speed / heading.length()
+                * match self.direction {
+                    Direction::Forward => 1f64,
+                    Direction::Backward => -1f64,
+                })
+    }
+
+    // leave the track where the train was last brought up to date, heading the way it was going
+    pub fn derail(&mut self, reason: DerailReason, tracks: &BTreeMap<u32, TrackPiece>) {
+        self.derailed = Some(Derailment {
+            position: tracks
+                .get(&self.current_track)
+                .unwrap()
+                .position(self.progress),
+            velocity: self.velocity(tracks),
+            time: tokio::time::Instant::now(),
+            reason,
+        });
+        self.dwelling = None;
+    }
+
+    // back on the track where it left it, starting off again from standstill
+    // the cars behind are laid out again from the first track that isn't kept, and off a track that
+    // isn't kept the train goes to the closest point of the new tracks, true when it moved
+    pub fn relocate(
+        &mut self,
+        kept: &BTreeSet<TrackID>,
+        tracks: &BTreeMap<u32, TrackPiece>,
+        new_tracks: &BTreeMap<u32, TrackPiece>,
+    ) -> bool {
+        if let Some(changed) = self.trail.iter().position(|(id, _)| !kept.contains(id)) {
+            self.trail.truncate(changed);
+        }
+        if kept.contains(&self.current_track) {
+            return false;
+        }
+        self.trail.clear();
+        let position = tracks
+            .get(&self.current_track)
+            .unwrap()
+            .position(self.progress);
+        (self.current_track, self.progress) = closest_progress(position, new_tracks).unwrap();
+        true
+    }
+
+    pub fn rerail(&mut self) {
+        self.derailed = None;
+        self.speed = 0f64;
+        self.target_speed = self.properties.speed;
+        self.braking = None;
+    }
+
+    fn image(&self) -> ImageSrc {
+        let car = &self.properties.cars[0];
+        match self.direction {
+            Direction::Forward => car.image_forward.clone(),
+            Direction::Backward => car.image_backward.clone(),
+        }
+    }
+
+    pub fn to_packet(&self, id: u32, tracks: &BTreeMap<u32, TrackPiece>) -> ServerPacket {
+        if let Some(derailment) = &self.derailed {
+            return ServerPacket::PacketDERAIL(
+                id,
+                derailment.position,
+                derailment.velocity,
+                derailment.time.elapsed(),
+                derailment.reason,
+                self.image(),
+            );
+        }
+        let now = tokio::time::Instant::now();
+        let speed = self.speed_at(now, tracks);
+        ServerPacket::PacketTRAIN(
+            id,
+            self.current_track,
+            self.progress_at(now, tracks),
+            match speed > 0f64 {
+                true => {
+                    Duration::from_secs_f64(tracks.get(&self.current_track).unwrap().length / speed)
+                }
+                false => Duration::MAX, // a stopped train never gets anywhere
+            },
+            self.direction,
+            speed,
+            self.acceleration(speed),
+            self.aimed_speed(),
+            self.image(),
+            self.trail.clone(),
+            self.properties
+                .cars
+                .iter()
+                .skip(1)
+                .map(|car| {
+                    (
+                        car.spacing,
+                        car.image_forward.clone(),
+                        car.image_backward.clone(),
+                    )
+                })
+                .collect(),
+        )
+    }
+}
+
+// the cars of two trains closest to each other, given where every car of both is
+pub fn closest_cars(a: &[Coord], b: &[Coord]) -> (Coord, Coord) {
+    a.iter()
+        .flat_map(|position_a| b.iter().map(move |position_b| (*position_a, *position_b)))
+        .min_by(|x, y| x.0.distance(x.1).total_cmp(&y.0.distance(y.1)))
+        .unwrap()
+}
+
+// when two running trains touch while closing in on each other, if they do between from and until;
+// neither train may leave its current track before until
+pub fn contact_time(
+    a: &TrainInstance,
+    b: &TrainInstance,
+    from: tokio::time::Instant,
+    until: tokio::time::Instant,
+    tracks: &BTreeMap<u32, TrackPiece>,
+) -> Option<tokio::time::Instant> {
+    let reach = (a.properties.length + b.properties.length) / 2f64;
+    // neither train goes faster than it is or is heading for
+    let closing_speed = a.speed.max(a.aimed_speed()) + b.speed.max(b.aimed_speed());
+    let horizon = until.saturating_duration_since(from).as_secs_f64();
+    let distance = |seconds: f64| {
+        let time = from + Duration::from_secs_f64(seconds);
+        let (position_a, position_b) = closest_cars(
+            &a.car_positions_at(time, tracks),
+            &b.car_positions_at(time, tracks),
+        );
+        position_a.distance(position_b)
+    };
+
+    // only trains coming within reach collide, ones already deep into each other
+    // (stacked in the layout or spawned onto one another) pass through
+    let mut previous = (0f64, distance(0f64));
+    if previous.1 < reach
+        && previous.1 > reach - COLLISION_STEP
+        && distance(COLLISION_PROBE) < previous.1
+    {
+        return Some(from);
+    }
+    if closing_speed <= 0f64 || previous.1 - closing_speed * horizon > reach {
+        return None;
+    }
+
+    let step = COLLISION_STEP / closing_speed;
+    while previous.0 < horizon {
+        let seconds = (previous.0 + step).min(horizon);
+        let current = distance(seconds);
+        if current < reach && previous.1 >= reach {
+            // narrow down to just after they touch
+            let (mut low, mut high) = (previous.0, seconds);
+            for _ in 0..32 {
+                let middle = (low + high) / 2f64;
+                match distance(middle) < reach {
+                    true => high = middle,
+                    false => low = middle,
+                }
+            }
+            return Some(from + Duration::from_secs_f64(high));
+        }
+        previous = (seconds, current);
+    }
+    None
+}
+
+// when trains are expected at the stations ahead of them, going at the speed they're aiming for
+// the whole way over the tracks the junctions are set for now, without waiting at signals
+pub fn upcoming_arrivals(
+    trains: &BTreeMap<TrainID, TrainInstance>,
+    tracks: &BTreeMap<u32, TrackPiece>,
+    junctions: &BTreeMap<JunctionID, Junction>,
+    stations: &BTreeMap<StationID, Station>,
+) -> Vec<StationArrivals> {
+    let now = tokio::time::Instant::now();
+    let mut arrivals: BTreeMap<StationID, Vec<Arrival>> = BTreeMap::new();
+    for (id, train) in trains.iter().filter(|(_, train)| train.derailed.is_none()) {
+        let mut train = train.clone();
+        train.catch_up(now, tracks);
+        let mut time = now;
+        if let Some((station_id, departure)) = train.dwelling {
+            arrivals.entry(station_id).or_default().push(Arrival {
+                train: *id,
+                arrival: 0f64,
+                departure: departure.saturating_duration_since(now).as_secs_f64(),
+            });
+            time = departure;
+            train.depart();
+        }
+        if train.target_speed <= 0f64 {
+            continue;
+        }
+
+        // going over every track both ways is as far ahead as it's worth looking
+        let mut just_entered = false;
+        for _ in 0..tracks.len() * 2 {
+            let track = tracks.get(&train.current_track).unwrap();
+            let mut ahead: Vec<(StationID, f64)> = track
+                .stations
+                .iter()
+                .copied()
+                .filter(|(_, progress)| match train.direction {
+                    Direction::Forward => {
+                        *progress > train.progress || (just_entered && *progress == 0f64)
+                    }
+                    Direction::Backward => {
+                        *progress < train.progress || (just_entered && *progress == 1f64)
+                    }
+                })
+                .collect();
+            ahead.sort_by(|a, b| {
+                train
+                    .distance_to(a.1, tracks)
+                    .total_cmp(&train.distance_to(b.1, tracks))
+            });
+
+            for (station_id, progress) in ahead {
+                if !train.stops_at(station_id) {
+                    continue;
+                }
+                time += Duration::from_secs_f64(
+                    train.distance_to(progress, tracks) / train.target_speed,
+                );
+                let departure = train.departure_from(station_id, &stations[&station_id], time);
+                arrivals.entry(station_id).or_default().push(Arrival {
+                    train: *id,
+                    arrival: time.saturating_duration_since(now).as_secs_f64(),
+                    departure: departure.saturating_duration_since(now).as_secs_f64(),
+                });
+                (time, train.progress) = (departure, progress);
+                train.dwelling = Some((station_id, departure));
+                train.depart();
+            }
+
+            let end = match train.direction {
+                Direction::Forward => 1f64,
+                Direction::Backward => 0f64,
+            };
+            time += Duration::from_secs_f64(train.distance_to(end, tracks) / train.target_speed);
+            (train.current_track, train.direction) =
+                next_track(train.current_track, train.direction, tracks, junctions);
+            train.progress = match train.direction {
+                Direction::Forward => 0f64,
+                Direction::Backward => 1f64,
+            };
+            just_entered = true;
+        }
+    }
+
+    stations
+        .iter()
+        .map(|(id, station)| {
+            let mut arrivals = arrivals.remove(id).unwrap_or_default();
+            arrivals.sort_by(|a, b| a.arrival.total_cmp(&b.arrival));
+            StationArrivals {
+                id: *id,
+                name: station.name.clone(),
+                arrivals,
+            }
+        })
+        .collect()
+}
+
+#[derive(serde::Serialize)]
+pub struct StationArrivals {
+    pub id: StationID,
+    pub name: String,
+    pub arrivals: Vec<Arrival>, // soonest first
+}
+
+#[derive(serde::Serialize)]
+pub struct Arrival {
+    pub train: TrainID,
+    pub arrival: f64,   // s from now, zero for a train already there
+    pub departure: f64, // s from now
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::TrackLayout;
+    use crate::track::kept_tracks;
+
+    fn straight_track() -> BTreeMap<u32, TrackPiece> {
+        let track = TrackLayout {
+            id: 0,
+            path: Bezier::Bezier2(Coord(0f64, 0f64), Coord(1000f64, 0f64)),
+            color: "#FFFFFF".into(),
+            thickness: 10f64,
+            start: 0,
+            end: 1,
+            block: None,
+        };
+        BTreeMap::from([(0, track.into())])
+    }
+
+    fn train(
+        progress: f64,
+        direction: Direction,
+        tracks: &BTreeMap<u32, TrackPiece>,
+    ) -> TrainInstance {
+        let mut layout = TrainLayout::new(0, 0, progress);
+        layout.direction = direction;
+        TrainInstance::from_layout(layout, tracks)
+    }
+
+    #[test]
+    fn trains_running_into_each_other_touch_half_their_lengths_apart() {
+        let tracks = straight_track();
+        let a = train(0.1f64, Direction::Forward, &tracks);
+        let mut b = train(0.9f64, Direction::Backward, &tracks);
+        b.updated = a.updated;
+        let until = a.updated + Duration::from_secs(10);
+        let contact = contact_time(&a, &b, a.updated, until, &tracks).unwrap();
+
+        // 800px apart closing in at 250px/s each, touching once 80px apart
+        let seconds = (contact - a.updated).as_secs_f64();
+        assert!((seconds - 720f64 / 500f64).abs() < 0.01f64, "{}", seconds);
+    }
+
+    #[test]
+    fn trains_running_apart_never_touch() {
+        let tracks = straight_track();
+        let a = train(0.4f64, Direction::Backward, &tracks);
+        let mut b = train(0.6f64, Direction::Forward, &tracks);
+        b.updated = a.updated;
+        let until = a.updated + Duration::from_secs(1);
+        assert_eq!(contact_time(&a, &b, a.updated, until, &tracks), None);
+    }
+
+    // a straight track running into a junction at its end
+    fn junction_ahead_layout() -> (BTreeMap<u32, TrackPiece>, BTreeMap<JunctionID, Junction>) {
+        let track = |id, path, start, end| {
+            let track = TrackLayout {
+                id,
+                path,
+                color: "#FFFFFF".into(),
+                thickness: 10f64,
+                start,
+                end,
+                block: None,
+            };
+            (id, TrackPiece::from(track))
+        };
+        let tracks = BTreeMap::from([
+            track(
+                0,
+                Bezier::Bezier2(Coord(0f64, 0f64), Coord(1000f64, 0f64)),
+                0,
+                1,
+            ),
+            track(
+                1,
+                Bezier::Bezier2(Coord(1000f64, 0f64), Coord(1100f64, 0f64)),
+                1,
+                2,
+            ),
+            track(
+                2,
+                Bezier::Bezier3(
+                    Coord(1000f64, 0f64),
+                    Coord(1050f64, 0f64),
+                    Coord(1100f64, 100f64),
+                ),
+                1,
+                3,
+            ),
+        ]);
+        let junctions = BTreeMap::from([(
+            0,
+            Junction {
+                node: 1,
+                track: 0,
+                sides: [1, 2],
+                side: false,
+            },
+        )]);
+        (tracks, junctions)
+    }
+
+    // a standing train setting off towards 250px/s
+    fn starting(tracks: &BTreeMap<u32, TrackPiece>) -> TrainInstance {
+        let mut train = train(0f64, Direction::Forward, tracks);
+        train.properties.acceleration = 100f64;
+        train.properties.deceleration = 200f64;
+        train.speed = 0f64;
+        train
+    }
+
+    #[test]
+    fn distance_after_speeds_up_then_cruises() {
+        let tracks = straight_track();
+        let train = starting(&tracks);
+        // 2.5s to get to 250px/s, 312.5px covered by then
+        assert!((train.distance_after(1f64) - 50f64).abs() < 1e-9);
+        assert!((train.distance_after(2.5f64) - 312.5f64).abs() < 1e-9);
+        assert!((train.distance_after(4f64) - 687.5f64).abs() < 1e-9);
+    }
+
+    #[test]
+    fn time_to_cover_undoes_distance_after() {
+        let tracks = straight_track();
+        let train = starting(&tracks);
+        for seconds in [0f64, 0.5f64, 2.5f64, 4f64] {
+            let time = train.time_to_cover(train.distance_after(seconds)).unwrap();
+            assert!((time - seconds).abs() < 1e-9, "{} {}", seconds, time);
+        }
+    }
+
+    #[test]
+    fn stopping_train_never_covers_more_than_it_takes_to_stop() {
+        let tracks = straight_track();
+        let mut train = starting(&tracks);
+        train.speed = 100f64;
+        train.target_speed = 0f64;
+        // 0.5s to stop, 25px covered by then
+        assert!((train.time_to_cover(18.75f64).unwrap() - 0.25f64).abs() < 1e-9);
+        assert_eq!(train.time_to_cover(30f64), None);
+    }
+
+    #[test]
+    fn trains_brake_in_time_to_take_junctions_at_junction_speed() {
+        let (tracks, junctions) = junction_ahead_layout();
+        let mut train = train(0f64, Direction::Forward, &tracks);
+        train.properties.deceleration = 200f64;
+
+        // (250^2 - 150^2) / 2 / 200 = 100px short of the junction, 900px away at 250px/s
+        let braking = train.braking_time(&tracks, &junctions).unwrap();
+        let seconds = (braking - train.updated).as_secs_f64();
+        assert!((seconds - 3.6f64).abs() < 1e-3, "{}", seconds);
+
+        // nothing to brake for without the junction
+        assert_eq!(train.braking_time(&tracks, &BTreeMap::new()), None);
+    }
+
+    #[test]
+    fn trains_stay_on_kept_tracks_and_move_off_the_rest() {
+        let tracks = straight_track();
+        let mut train = train(0.3f64, Direction::Forward, &tracks);
+
+        // the cars behind are only laid out again from the first track that isn't kept
+        train.trail = vec![(0, Direction::Forward), (1, Direction::Forward)];
+        assert!(!train.relocate(&BTreeSet::from([0]), &tracks, &tracks));
+        assert_eq!((train.current_track, train.progress), (0, 0.3f64));
+        assert_eq!(train.trail, vec![(0, Direction::Forward)]);
+
+        // the same track laid lower down, the train drops onto it
+        let lowered = TrackLayout {
+            id: 0,
+            path: Bezier::Bezier2(Coord(0f64, 100f64), Coord(1000f64, 100f64)),
+            color: "#FFFFFF".into(),
+            thickness: 10f64,
+            start: 0,
+            end: 1,
+            block: None,
+        };
+        let new_tracks = BTreeMap::from([(0, lowered.into())]);
+        let kept = kept_tracks(&tracks, &new_tracks);
+        assert!(train.relocate(&kept, &tracks, &new_tracks));
+        assert_eq!(train.current_track, 0);
+        assert!(
+            (train.progress - 0.3f64).abs() < 0.01f64,
+            "{}",
+            train.progress
+        );
+        assert!(train.trail.is_empty());
+    }
+}
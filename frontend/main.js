@@ -11,7 +11,11 @@ let ask_attempt = 0;
 let debugMode = false;
 let dragMode = true;
 
-while (Number.isNaN(relative_x) || relative_x < -4000 || 4000 < relative_x) {
+// screens named by the screen parameter of the page url are placed by the server instead of asking
+const screen_name = new URL(window.location.href).searchParams.get("screen");
+let scale = 1; // screen px per world px
+
+while (!screen_name && (Number.isNaN(relative_x) || relative_x < -4000 || 4000 < relative_x)) {
     relative_x = Number(window.prompt("Relative x?", "0"));
     ask_attempt++;
     if (ask_attempt > 10) {
@@ -20,7 +24,7 @@ while (Number.isNaN(relative_x) || relative_x < -4000 || 4000 < relative_x) {
 }
 
 ask_attempt = 0;
-while (Number.isNaN(relative_y) || relative_y < -2000 || 2000 < relative_y) {
+while (!screen_name && (Number.isNaN(relative_y) || relative_y < -2000 || 2000 < relative_y)) {
     relative_y = Number(window.prompt("Relative y?", "0"));
     ask_attempt++;
     if (ask_attempt > 10) {
@@ -28,6 +32,11 @@ while (Number.isNaN(relative_y) || relative_y < -2000 || 2000 < relative_y) {
     }
 }
 
+if (Number.isNaN(relative_x))
+    relative_x = 0;
+if (Number.isNaN(relative_y))
+    relative_y = 0;
+
 // TODO: ask for x y boundaries and scale track base on view port size
 document.cookie = "relative_x=" + relative_x;
 document.cookie = "relative_y=" + relative_y;
@@ -51,6 +60,15 @@ let run_time = 1000.0; //?ms
 let trainlist = new Map();
 let tracklist = new Map();
 let trainposition = [];
+let junctionlist = new Map();
+const junction_radius = 15;
+let signallist = new Map();
+const signal_radius = 8;
+const derail_slide_time = 500; // ms, how quickly a derailed train comes to rest
+let collisionlist = [];
+const collision_flash_time = 600; // ms
+let shake = null; // the vibration the screen is shaking with
+const shake_amplitude = 12; // px at full intensity
 
 function drawRotatedImg(ctx, rotation_center_x, rotation_center_y, rotation_degree, object_x, object_y, img) {
     ctx.save();
@@ -79,6 +97,16 @@ function bezierPoint(cordlist, current_t) {
     return {x: x_pos, y: y_pos};
 }
 
+// turn a fraction of the track length into bezier t using the table sent with the track
+function progressToT(track, progress) {
+    let table = track.arc_table;
+    if (!table || table.length < 2 || progress <= 0 || progress >= 1)
+        return progress;
+    let position = progress * (table.length - 1);
+    let i = Math.floor(position);
+    return table[i] + (table[i + 1] - table[i]) * (position - i);
+}
+
 function bezierDerivative(coords, t) {
     const n = (coords.length / 2) - 2; // Number of control points
 
@@ -224,6 +252,127 @@ function drawTrack(ctx, track) {
     }
 }
 
+// the end of the selected side where it meets the junction's track
+function junctionEnd(junction) {
+    let track = tracklist.get(junction.track_id);
+    let side = tracklist.get(junction.sides[junction.side]);
+    if (!track || !side)
+        return null;
+
+    let best = null;
+    [0, 1].forEach(side_t => {
+        let point = bezierPoint(side.cordlist, side_t);
+        [0, 1].forEach(track_t => {
+            let other = bezierPoint(track.cordlist, track_t);
+            let distance = Math.sqrt(Math.pow(point.x - other.x, 2) + Math.pow(point.y - other.y, 2));
+            if (best == null || distance < best.distance) {
+                best = { t: side_t, point: point, distance: distance };
+            }
+        });
+    });
+    return best;
+}
+
+// px a train covers in the given seconds, speeding up or slowing down until it reaches its target speed
+function travelledDistance(train, seconds) {
+    if (train.acceleration == 0)
+        return train.speed * seconds;
+    let settle = Math.max(0, Math.min(seconds, (train.target_speed - train.speed) / train.acceleration));
+    return train.speed * settle + train.acceleration * settle * settle / 2 + train.target_speed * (seconds - settle);
+}
+
+// a train or car sprite at progress along a track, clicking it clicks the train
+function drawTrainSprite(ctx, id, track, progress, img) {
+    let current_t = progressToT(track, progress);
+    let point = bezierPoint(track.cordlist, current_t);
+    trainposition.push({ id: id, x: point.x, y: point.y });
+    let dresult = bezierDerivative(track.cordlist, current_t);
+    let deg = Math.atan2(dresult.dy, dresult.dx) * 180 / Math.PI;
+    drawRotatedImg(ctx, point.x, point.y, deg, point.x - train_width / 2, point.y - train_height, img);
+}
+
+// where the car distance px behind the leading one is, following the tracks the train came over
+function carPlace(train, track, progress, distance) {
+    let behind = (train.direction == 1 ? progress : 1 - progress) * track.length;
+    if (distance <= behind || train.trail.length == 0)
+        return { track: track, progress: progress - train.direction * distance / track.length, direction: train.direction };
+    distance -= behind;
+    for (let i = 0; i < train.trail.length; i++) {
+        let trail_track = tracklist.get(train.trail[i].track_id);
+        if (!trail_track)
+            return null;
+        if (distance <= trail_track.length || i == train.trail.length - 1) {
+            let fraction = Math.min(distance, trail_track.length) / trail_track.length;
+            let direction = train.trail[i].direction;
+            return { track: trail_track, progress: direction == 1 ? 1 - fraction : fraction, direction: direction };
+        }
+        distance -= trail_track.length;
+    }
+}
+
+// a derailed train keeps sliding the way it was going, tipping over until it comes to rest
+function drawDerailedTrain(ctx, train, id, time) {
+    if (Number.isNaN(train.derail_start))
+        train.derail_start = time - train.derail_elapsed;
+
+    let rest = 1 - Math.exp(-(time - train.derail_start) / derail_slide_time);
+    let x_pos = train.derail_x + train.derail_vx * derail_slide_time / 1000 * rest;
+    let y_pos = train.derail_y + train.derail_vy * derail_slide_time / 1000 * rest;
+    trainposition.push({ id: id, x: x_pos, y: y_pos });
+
+    let deg = Math.atan2(train.derail_vy, train.derail_vx) * 180 / Math.PI + 60 * rest;
+    drawRotatedImg(ctx, x_pos, y_pos, deg, x_pos - train_width / 2, y_pos - train_height, train.img);
+}
+
+// a ring spreading out from where two trains ran into each other, gone after collision_flash_time
+function drawCollision(ctx, collision, time) {
+    if (Number.isNaN(collision.start))
+        collision.start = time;
+    let age = (time - collision.start) / collision_flash_time;
+
+    ctx.beginPath();
+    ctx.strokeStyle = "rgba(255, 80, 0, " + (1 - age) + ")";
+    ctx.lineWidth = 8;
+    ctx.arc(collision.x, collision.y, 20 + 60 * age, 0, 2 * Math.PI);
+    ctx.stroke();
+}
+
+function drawJunction(ctx, junction) {
+    let end = junctionEnd(junction);
+    if (!end)
+        return;
+    let track = tracklist.get(junction.sides[junction.side]);
+
+    // highlight the selected side next to the junction
+    ctx.beginPath();
+    ctx.strokeStyle = "#FFCC00";
+    ctx.lineWidth = track.thickness / 2;
+    ctx.moveTo(end.point.x, end.point.y);
+    for (let i = 1; i <= 10; i++) {
+        let point = bezierPoint(track.cordlist, Math.abs(end.t - i / 50));
+        ctx.lineTo(point.x, point.y);
+    }
+    ctx.stroke();
+
+    ctx.beginPath();
+    ctx.fillStyle = "#FFCC00";
+    ctx.arc(end.point.x, end.point.y, junction_radius, 0, 2 * Math.PI);
+    ctx.fill();
+}
+
+// a lamp for every signal of the block, red while a train is in it
+function drawSignal(ctx, signal) {
+    signal.positions.forEach(position => {
+        ctx.beginPath();
+        ctx.fillStyle = signal.aspect == "red" ? "#FF2020" : "#20D020";
+        ctx.strokeStyle = "#202020";
+        ctx.lineWidth = 3;
+        ctx.arc(position.x, position.y, signal_radius, 0, 2 * Math.PI);
+        ctx.fill();
+        ctx.stroke();
+    });
+}
+
 function redraw(time) {
     // /**
     // * @param trainlist a list of param including (trainid, trackid)
@@ -236,6 +385,21 @@ function redraw(time) {
 
     main_context.clearRect(0, 0, main_canvas.width, main_canvas.height);
     main_context.save();
+
+    // shaking jolts the whole picture around, steadily for a rumble and fading out for bumps and crashes
+    if (shake) {
+        if (Number.isNaN(shake.start))
+            shake.start = time;
+        let age = (time - shake.start) / shake.duration;
+        if (age >= 1) {
+            shake = null;
+        } else {
+            let strength = shake_amplitude * shake.intensity * (shake.pattern == "rumble" ? 1 : 1 - age);
+            main_context.translate((Math.random() * 2 - 1) * strength, (Math.random() * 2 - 1) * strength);
+        }
+    }
+
+    main_context.scale(scale, scale);
     main_context.translate(-relative_x, -relative_y);
 
     if (img == -1) {//? default image
@@ -248,36 +412,43 @@ function redraw(time) {
         drawTrack(main_context, track);
     });
 
+    junctionlist.forEach(junction => {
+        drawJunction(main_context, junction);
+    });
+
+    signallist.forEach(signal => {
+        drawSignal(main_context, signal);
+    });
+
     trainposition = [];
     trainlist.forEach((train, id) => {
-        if (Number.isNaN(train.movement_start)) {
-            if (train.direction == 1) {
-                train.movement_start = time - Number(train.start_t) * Number(train.duration);
-            } else {
-                train.movement_start = time - (1 - Number(train.start_t)) * Number(train.duration);
-            }
+        if (train.derailed) {
+            drawDerailedTrain(main_context, train, id, time);
+            return;
         }
+        if (Number.isNaN(train.movement_start))
+            train.movement_start = time;
 
-        let cordlist = tracklist.get(train.track_id).cordlist;
-        let current_t = (time - train.movement_start) / train.duration;
-        if (train.direction == -1) {
-            current_t = 1 - current_t;
-        }
-        if (current_t > 1.1 || current_t < -0.1)
+        let track = tracklist.get(train.track_id);
+        if (!track)
             return;
-        let point = bezierPoint(cordlist, current_t);
-        let x_pos = point.x;
-        let y_pos = point.y;
-        let trainpositionitem = {};
-        trainpositionitem.id=id;
-        trainpositionitem.x=x_pos;
-        trainpositionitem.y=y_pos;
-        trainposition.push(trainpositionitem);
-        //! not handling out of bound problem
-        // now detrive
-        let dresult = bezierDerivative(cordlist, current_t);
-        let deg = Math.atan2(dresult.dy, dresult.dx) * 180 / Math.PI;
-        drawRotatedImg(main_context, x_pos, y_pos, deg, x_pos - train_width / 2, y_pos - train_height, train.img);
+        let progress = train.start_t + train.direction * travelledDistance(train, (time - train.movement_start) / 1000) / track.length;
+        if (progress > 1.1 || progress < -0.1)
+            return;
+        drawTrainSprite(main_context, id, track, progress, train.img);
+
+        let offset = 0;
+        train.cars.forEach(car => {
+            offset += car.spacing;
+            let place = carPlace(train, track, progress, offset);
+            if (place)
+                drawTrainSprite(main_context, id, place.track, place.progress, place.direction == 1 ? car.img_forward : car.img_backward);
+        });
+    });
+
+    collisionlist = collisionlist.filter(collision => Number.isNaN(collision.start) || time - collision.start < collision_flash_time);
+    collisionlist.forEach(collision => {
+        drawCollision(main_context, collision, time);
     });
 
     main_context.restore();
@@ -290,8 +461,18 @@ let url = new URL(window.location.href);
 console.log((url.protocol == "http:" ? "ws:" : "wss:") + "//" + url.host + url.pathname + "ws");
 let socket = new WebSocket((url.protocol == "http:" ? "ws:" : "wss:") + "//" + url.host + url.pathname + "ws");
 
+// tell the server which part of the world this screen shows, in world px
+function sendViewport() {
+    if (socket.readyState != WebSocket.OPEN)
+        return;
+    socket.send("viewport\n" + relative_x + ";" + relative_y + " " + main_canvas.width / scale + ";" + main_canvas.height / scale
+        + (screen_name ? "\n" + screen_name.replace(/\n/g, " ") : ""));
+}
+
+window.addEventListener("resize", sendViewport);
+
 socket.onopen = (event) => {
-    // socket.send("position\n" + left_bound + " " + right_bound);
+    sendViewport();
     socket.onmessage = (msg) => {
         // console.log(msg);
         let msg_split = msg.data.split("\n");
@@ -311,13 +492,40 @@ socket.onopen = (event) => {
                 } else {
                     new_train.direction = -1;
                 }
+                new_train.speed = Number(args[5]);
+                new_train.acceleration = Number(args[6]);
+                new_train.target_speed = Number(args[7]);
                 new_train.img = new Image();
                 new_train.img.src = msg_split[2];
                 new_train.movement_start = NaN;
 
+                // trains with cars list the tracks behind them and then every car
+                new_train.trail = [];
+                new_train.cars = [];
+                if (msg_split.length > 3) {
+                    new_train.trail = msg_split[3].split(" ").filter(x => x).map(x => {
+                        let entry = x.split(";");
+                        return { track_id: Number(entry[0]), direction: entry[1] == "forward" ? 1 : -1 };
+                    });
+                    for (let i = 4; i < msg_split.length; i++) {
+                        let car_args = msg_split[i].split(" ");
+                        let car = {};
+                        car.spacing = Number(car_args[0]);
+                        car.img_forward = new Image();
+                        car.img_forward.src = car_args[1];
+                        car.img_backward = new Image();
+                        car.img_backward.src = car_args[2];
+                        new_train.cars.push(car);
+                    }
+                }
+
                 trainlist.set(Number(args[0]), new_train);
                 break;
             case "track":
+                // the track list replaces the old one, junctions follow right after it
+                tracklist.clear();
+                junctionlist.clear();
+                signallist.clear();
                 for (i = 2; i < msg_split.length; i++) {
                     args = msg_split[i].split(" ");
                     let track = {};
@@ -326,10 +534,79 @@ socket.onopen = (event) => {
                     track.cordlist = cordlist
                     track.color = args[2];
                     track.thickness = Number(args[3]);
+                    track.length = Number(args[4]);
+                    track.arc_table = args[5].split(";").map(x => Number(x));
 
                     tracklist.set(Number(args[0]), track);
                 }
                 break;
+            case "derail":
+                args = msg_split[1].split(" ");
+                let derail_position = args[1].split(";").map(x => Number(x));
+                let derail_velocity = args[2].split(";").map(x => Number(x));
+                let derailed_train = {};
+                derailed_train.derailed = true;
+                derailed_train.derail_x = derail_position[0];
+                derailed_train.derail_y = derail_position[1];
+                derailed_train.derail_vx = derail_velocity[0];
+                derailed_train.derail_vy = derail_velocity[1];
+                derailed_train.derail_elapsed = Number(args[3]);
+                derailed_train.derail_start = NaN;
+                derailed_train.img = new Image();
+                derailed_train.img.src = msg_split[2];
+
+                trainlist.set(Number(args[0]), derailed_train);
+                break;
+            case "collision":
+                args = msg_split[1].split(" ");
+                let collision_position = args[2].split(";").map(x => Number(x));
+                collisionlist.push({ x: collision_position[0], y: collision_position[1], start: NaN });
+                break;
+            case "junction":
+                args = msg_split[1].split(" ");
+                let junction_tracks = msg_split[2].split(" ").map(x => Number(x));
+                let junction = {};
+                junction.side = Number(args[1]);
+                junction.track_id = junction_tracks[0];
+                junction.sides = [junction_tracks[1], junction_tracks[2]];
+
+                junctionlist.set(Number(args[0]), junction);
+                break;
+            case "signal":
+                args = msg_split[1].split(" ");
+                let signal = {};
+                signal.aspect = args[1];
+                signal.positions = msg_split[2] ? msg_split[2].split(" ").map(coord => {
+                    let position = coord.split(";").map(x => Number(x));
+                    return { x: position[0], y: position[1] };
+                }) : [];
+
+                signallist.set(Number(args[0]), signal);
+                break;
+            case "vibrate":
+                args = msg_split[1].split(" ");
+                shake = { pattern: args[0], intensity: Number(args[1]), duration: Number(args[2]), start: NaN };
+                // vibration motors are only on or off, so the intensity is how much of every 20ms it's on
+                if (navigator.vibrate) {
+                    let on = Math.max(1, Math.round(20 * shake.intensity));
+                    let pulses = [];
+                    for (let t = 0; t < shake.duration; t += 20)
+                        pulses.push(on, 20 - on);
+                    navigator.vibrate(pulses);
+                }
+                break;
+            case "placement":
+                // the server owns where this screen is, dragging would only fight it
+                args = msg_split[1].split(" ");
+                let placement = args[0].split(";").map(x => Number(x));
+                relative_x = placement[0];
+                relative_y = placement[1];
+                scale = Number(args[1]);
+                dragMode = false;
+                document.cookie = "relative_x=" + relative_x;
+                document.cookie = "relative_y=" + relative_y;
+                sendViewport();
+                break;
         }
     };
     socket.onclose = (msg) => {
@@ -340,7 +617,7 @@ socket.onopen = (event) => {
 
 // update click on demand
 window.addEventListener("click", function (event) {
-    mousePos = { x: event.clientX + relative_x, y: event.clientY + relative_y};
+    mousePos = { x: event.clientX / scale + relative_x, y: event.clientY / scale + relative_y};
     r=Math.sqrt(Math.pow(train_width/2,2)+Math.pow(train_height/2,2))
     // time complexity (o(n))
     trainposition.forEach(pos=>{
@@ -349,14 +626,29 @@ window.addEventListener("click", function (event) {
             socket.send("click\n" + pos.id + " " + Number(event.ctrlKey) + "," + Number(event.shiftKey) + "," + Number(event.altKey));
         }
     });
+    // clicking a junction switches it to the other side
+    junctionlist.forEach((junction, id) => {
+        let end = junctionEnd(junction);
+        if (!end)
+            return;
+        if (Math.sqrt(Math.pow(mousePos.x - end.point.x, 2) + Math.pow(mousePos.y - end.point.y, 2)) <= junction_radius) {
+            socket.send("movejunction\n" + id + " " + (1 - junction.side));
+        }
+    });
 });
 
 // make window draggable
 window.addEventListener("mousemove", event => {
     if(event.buttons === 1 && dragMode) {
-        relative_x -= event.movementX;
-        relative_y -= event.movementY;
+        relative_x -= event.movementX / scale;
+        relative_y -= event.movementY / scale;
         document.cookie = "relative_x=" + relative_x;
         document.cookie = "relative_y=" + relative_y;
     }
+});
+
+// the server learns where the screen ended up once dragging is done
+window.addEventListener("mouseup", event => {
+    if (dragMode)
+        sendViewport();
 });
\ No newline at end of file